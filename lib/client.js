@@ -1,9 +1,27 @@
 import Cookies from "js-cookie";
 
+// `playback_rate` is "<rate>@<anchor>", where `anchor` is the wall-clock time (in seconds) the
+// rate took effect at, followed by "@paused" if time is paused. Without it, time passes at 1x.
+const [playbackRate, playbackAnchor, paused] = (Cookies.get("playback_rate") ?? "1@0").split("@");
+
 window.Before = {
   time: parseInt(Cookies.get("offset_sec"), 10),
+  rate: Number(playbackRate),
+  anchor: Number(playbackAnchor),
+  paused: paused === "paused",
 };
 
+// The perceived time, in milliseconds, at wall-clock time `now`.
+function perceivedTime(now) {
+  const { time, rate, anchor, paused } = window.Before;
+  return (anchor - time) * 1000 + (now - anchor * 1000) * (paused ? 0 : rate);
+}
+
+function rateParam() {
+  const { rate, anchor, paused } = window.Before;
+  return `${rate}@${anchor}${paused ? "@paused" : ""}`;
+}
+
 // cursed glue
 const unbind = Function.bind.bind(Function.bind);
 function instantiate(constructor, args) {
@@ -22,7 +40,7 @@ Date = ((Date) => {
     }
 
     let date = instantiate(CurrentDate, args);
-    date = instantiate(CurrentDate, [date.getTime() - window.Before.time * 1000]);
+    date = instantiate(CurrentDate, [perceivedTime(date.getTime())]);
     return date;
   }
 
@@ -51,7 +69,10 @@ const TrueSource = EventSource;
 /* eslint-disable-next-line no-global-assign */
 EventSource = ((EventSource) => {
   function TrickSource(url, options) {
-    return instantiate(TrueSource, [`${url}?_before_offset_time=${window.Before.time}`, options]);
+    return instantiate(TrueSource, [
+      `${url}?_before_offset_time=${window.Before.time}&_before_rate=${encodeURIComponent(rateParam())}`,
+      options,
+    ]);
   }
 
   Object.getOwnPropertyNames(EventSource).forEach((n) => {
@@ -74,5 +95,6 @@ fetch = (url, options) =>
     headers: {
       ...options?.headers,
       "X-Before-Time": window.Before.time,
+      "X-Before-Rate": rateParam(),
     },
   });
@@ -0,0 +1,193 @@
+//! Batching of single-entity lookups.
+//!
+//! Route and stream code tends to look up entities one ID at a time, often many at once for the
+//! same point in time. The first lookup for a given entity type and time becomes the leader of a
+//! batch: it waits a few milliseconds for other lookups to join, then fetches every ID in the batch
+//! with one comma-separated `id` query and hands each lookup its own entities.
+//!
+//! If the leader goes away before sending results (its request was dropped, or the fetch failed),
+//! the rest of the batch falls back to fetching on their own.
+
+use crate::chronicler::Version;
+use crate::fetch::fetch_entities;
+use crate::time::DateTime;
+use crate::Config;
+use anyhow::Result;
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration as StdDuration;
+use tokio::sync::watch;
+
+/// The most IDs sent in one batched query, to keep URLs to a reasonable length.
+const MAX_BATCH_SIZE: usize = 100;
+
+type Key = (&'static str, DateTime);
+type Entities = Arc<HashMap<String, Vec<Version<Box<RawValue>>>>>;
+
+#[derive(Debug, Default)]
+pub(crate) struct Batcher {
+    /// Lookups are not batched if this is zero.
+    pub(crate) window: StdDuration,
+    pending: Mutex<HashMap<Key, Batch>>,
+}
+
+#[derive(Debug)]
+struct Batch {
+    ids: Vec<String>,
+    tx: watch::Sender<Option<Entities>>,
+}
+
+enum Role {
+    Leader,
+    Follower(watch::Receiver<Option<Entities>>),
+    Alone,
+}
+
+/// Removes a batch from `pending` if its leader is dropped while waiting for the batch to fill.
+struct Leader<'a> {
+    batcher: &'a Batcher,
+    key: Key,
+    waiting: bool,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        if self.waiting {
+            self.batcher.pending.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+impl Batcher {
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.window.is_zero()
+    }
+
+    /// Fetches the versions of entity `id` at `time`, batched with other concurrent lookups.
+    pub(crate) async fn fetch(
+        &self,
+        config: &Config,
+        ty: &'static str,
+        id: String,
+        time: DateTime,
+    ) -> Result<Vec<Version<Box<RawValue>>>> {
+        let key = (ty, time);
+        let role = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get_mut(&key) {
+                Some(batch) if batch.ids.len() < MAX_BATCH_SIZE => {
+                    if !batch.ids.contains(&id) {
+                        batch.ids.push(id.clone());
+                    }
+                    Role::Follower(batch.tx.subscribe())
+                }
+                // the batch for this key is full, so don't start another one
+                Some(_) => Role::Alone,
+                None => {
+                    let (tx, _) = watch::channel(None);
+                    pending.insert(
+                        key,
+                        Batch {
+                            ids: vec![id.clone()],
+                            tx,
+                        },
+                    );
+                    Role::Leader
+                }
+            }
+        };
+
+        match role {
+            Role::Leader => self.lead(config, key, id).await,
+            Role::Follower(mut rx) => {
+                if rx.changed().await.is_ok() {
+                    if let Some(entities) = rx.borrow().as_ref() {
+                        return Ok(entities.get(&id).cloned().unwrap_or_default());
+                    }
+                }
+                fetch_entities(config, ty, Some(id), time).await
+            }
+            Role::Alone => fetch_entities(config, ty, Some(id), time).await,
+        }
+    }
+
+    async fn lead(
+        &self,
+        config: &Config,
+        key: Key,
+        id: String,
+    ) -> Result<Vec<Version<Box<RawValue>>>> {
+        let mut leader = Leader {
+            batcher: self,
+            key,
+            waiting: true,
+        };
+        tokio::time::sleep(self.window).await;
+        let batch = self.pending.lock().unwrap().remove(&key);
+        leader.waiting = false;
+        let batch = match batch {
+            Some(batch) => batch,
+            None => return fetch_entities(config, key.0, Some(id), key.1).await,
+        };
+
+        let (ty, time) = key;
+        log::debug!("batched {} {ty} lookups at {time}", batch.ids.len());
+        let mut entities = HashMap::<_, Vec<_>>::new();
+        for version in fetch_entities(config, ty, Some(batch.ids.join(",")), time).await? {
+            entities
+                .entry(version.entity_id.clone())
+                .or_default()
+                .push(version);
+        }
+        let entities = Arc::new(entities);
+        // there might not be any followers, which is fine
+        let _ = batch.tx.send(Some(entities.clone()));
+        Ok(entities.get(&id).cloned().unwrap_or_default())
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn batch_concurrent_lookups() {
+    use crate::source::fixture::Fixture;
+    use crate::time::datetime;
+    use crate::Config;
+    use serde_json::json;
+
+    let fixture = Fixture::new(|query| {
+        let items = (query.id.as_deref().unwrap_or_default().split(','))
+            .map(|id| json!({ "entityId": id, "validFrom": "2021-03-01T00:00:00Z", "data": id }))
+            .collect::<Vec<_>>();
+        json!({ "items": items })
+    });
+    let queries = fixture.queries();
+    let mut config = Config::default();
+    config.source = Box::new(fixture);
+    config.batcher.window = StdDuration::from_millis(5);
+
+    let time = datetime!(2021-03-01 01:00:00 UTC);
+    let fetch = |id: &str| {
+        let config = &config;
+        let id = id.to_owned();
+        async move {
+            config
+                .fetch::<String>("Player", Some(id), time)
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+        }
+    };
+    let (a, b, c) = tokio::join!(fetch("a"), fetch("b"), fetch("a"));
+    assert_eq!(
+        (a, b, c),
+        (vec!["a".into()], vec!["b".into()], vec!["a".into()])
+    );
+    let queries = queries.lock().unwrap();
+    assert_eq!(
+        queries.iter().map(|q| q.id.as_deref()).collect::<Vec<_>>(),
+        [Some("a,b")]
+    );
+}
@@ -14,6 +14,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Number;
 use std::fmt::{self, Display};
 use std::str::FromStr;
+use std::sync::Arc;
 use time::Duration;
 use uuid::Uuid;
 
@@ -140,7 +141,7 @@ pub(crate) struct BetResponse {
 
 #[post("/api/bet", data = "<bet>")]
 pub(crate) async fn bet(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     cookies: &CookieJar<'_>,
     bet: Json<Bet>,
 ) -> Result<BetResult> {
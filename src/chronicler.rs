@@ -1,4 +1,6 @@
+use crate::metrics::METRICS;
 use crate::time::{datetime, DateTime};
+use crate::upstream::Priority;
 use crate::Config;
 use anyhow::Result;
 use derive_builder::Builder;
@@ -7,6 +9,7 @@ use rocket::response::stream::stream;
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 use std::marker::PhantomData;
+use std::time::Instant;
 
 pub(crate) fn fix_id(v: Box<RawValue>, time: DateTime) -> anyhow::Result<Box<RawValue>> {
     const ID_EPOCH: DateTime = datetime!(2020-08-23 23:23:00 UTC);
@@ -61,54 +64,89 @@ impl<T> ApiVersion for V2<T> {
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
-#[derive(Debug, Default, Serialize, Builder)]
+#[derive(Debug, Default, Clone, Serialize, Builder)]
 #[builder(derive(Debug, Clone), pattern = "owned")]
 pub(crate) struct Request<V> {
     #[builder(default, setter(skip))]
     #[serde(skip)]
     spooky: PhantomData<V>,
+    #[builder(default, setter(skip))]
+    #[serde(skip)]
+    pub(crate) version: &'static str,
     #[serde(skip)]
-    route: &'static str,
+    pub(crate) route: &'static str,
 
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    page: Option<String>,
+    pub(crate) page: Option<String>,
     #[builder(default, setter(strip_option))]
     #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
-    ty: Option<&'static str>,
+    pub(crate) ty: Option<&'static str>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    count: Option<usize>,
+    pub(crate) count: Option<usize>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    order: Option<Order>,
+    pub(crate) order: Option<Order>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    id: Option<String>,
+    pub(crate) id: Option<String>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    at: Option<DateTime>,
+    pub(crate) at: Option<DateTime>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    after: Option<DateTime>,
+    pub(crate) after: Option<DateTime>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    before: Option<DateTime>,
+    pub(crate) before: Option<DateTime>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    started: Option<bool>,
+    pub(crate) started: Option<bool>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    sim: Option<&'static str>,
+    pub(crate) sim: Option<&'static str>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    season: Option<i64>,
+    pub(crate) season: Option<i64>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    day: Option<i64>,
+    pub(crate) day: Option<i64>,
     #[builder(default, setter(strip_option))]
     #[serde(skip_serializing_if = "Option::is_none")]
-    game: Option<String>,
+    pub(crate) game: Option<String>,
+
+    #[builder(default)]
+    #[serde(skip)]
+    pub(crate) priority: Priority,
+}
+
+/// A [`Request`] with its response format erased, as handed to a
+/// [`DataSource`](crate::source::DataSource).
+pub(crate) type Query = Request<()>;
+
+impl<V: ApiVersion> Request<V> {
+    fn into_query(self) -> Query {
+        Request {
+            spooky: PhantomData,
+            version: V::VERSION,
+            route: self.route,
+            page: self.page,
+            ty: self.ty,
+            count: self.count,
+            order: self.order,
+            id: self.id,
+            at: self.at,
+            after: self.after,
+            before: self.before,
+            started: self.started,
+            sim: self.sim,
+            season: self.season,
+            day: self.day,
+            game: self.game,
+            priority: self.priority,
+        }
+    }
 }
 
 impl<V: ApiVersion> RequestBuilder<V> {
@@ -116,16 +154,16 @@ impl<V: ApiVersion> RequestBuilder<V> {
     where
         for<'de> V::Format: Deserialize<'de>,
     {
-        let request = self.build()?;
-        let url = format!(
-            "{}{}/{}?{}",
-            config.chronicler_base_url,
-            V::VERSION,
-            &request.route,
-            serde_urlencoded::to_string(&request)?
+        let query = self.build()?.into_query();
+        let start = Instant::now();
+        let result = config.source.get(&query).await;
+        METRICS.chronicler_request(
+            format!("{}/{}", query.version, query.route),
+            query.ty,
+            result.is_ok(),
+            start.elapsed(),
         );
-        log::debug!("chronicler request: {}", url);
-        Ok(config.client.get(url).send().await?.json().await?)
+        Ok(serde_json::from_str(&result?)?)
     }
 }
 
@@ -133,6 +171,30 @@ impl<T> RequestBuilder<V1<T>> {
     pub(crate) fn v1(route: &'static str) -> RequestBuilder<V1<T>> {
         RequestBuilder::default().route(route)
     }
+
+    pub(crate) fn paged_json<'a>(
+        self,
+        config: &'a Config,
+    ) -> impl StreamTrait<Item = Result<T>> + 'a
+    where
+        for<'de> T: Deserialize<'de> + 'a,
+    {
+        stream! {
+            let response = self.clone().json(config).await?;
+            for item in response.data {
+                yield Ok(item);
+            }
+            let mut next_page = response.next_page;
+
+            while let Some(page) = next_page {
+                let response = self.clone().page(page).json(config).await?;
+                for item in response.data {
+                    yield Ok(item);
+                }
+                next_page = response.next_page;
+            }
+        }
+    }
 }
 
 impl<T> RequestBuilder<V2<T>> {
@@ -177,6 +239,9 @@ pub(crate) enum Order {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Data<T> {
+    /// Only set by routes that page, like `games/updates`.
+    #[serde(default)]
+    pub(crate) next_page: Option<String>,
     pub(crate) data: Vec<T>,
 }
 
@@ -187,10 +252,12 @@ pub(crate) struct Versions<T> {
     pub(crate) items: Vec<Version<T>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Version<T> {
     pub(crate) valid_from: DateTime,
+    #[serde(default)]
+    pub(crate) valid_to: Option<DateTime>,
     pub(crate) entity_id: String,
     pub(crate) data: T,
 }
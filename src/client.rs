@@ -1,6 +1,6 @@
 use crate::config::Config;
 use crate::media::{self, Static};
-use crate::offset::OffsetTime;
+use crate::offset::AutoPaused;
 use crate::site::AssetSet;
 use crate::time::{datetime, DateTime};
 use crate::Result;
@@ -12,6 +12,7 @@ use rocket::request::FromRequest;
 use rocket::response::Redirect;
 use rocket::Responder;
 use rocket::{catch, get, Request, State};
+use std::sync::Arc;
 use textnonce::TextNonce;
 
 const EYES_FIX_RANGE: Range<DateTime> =
@@ -19,18 +20,18 @@ const EYES_FIX_RANGE: Range<DateTime> =
 
 #[get("/")]
 pub(crate) async fn index(
-    time: Option<OffsetTime>,
-    config: &State<Config>,
+    clock: Option<AutoPaused>,
+    config: &State<Arc<Config>>,
 ) -> Result<Response<'_>> {
-    let time = match time {
-        Some(time) => time,
+    let time = match clock {
+        Some(clock) => clock.0.now(),
         None => return Ok(Response::Redirect(Redirect::to("/_before/start"))),
     };
 
-    crate::site::update_cache(config, time.0).await?;
+    crate::site::update_cache(config, time).await?;
 
     let nonce = TextNonce::sized_urlsafe(24).map_err(|err| anyhow!(err))?;
-    let body_class = if EYES_FIX_RANGE.contains(&time.0) {
+    let body_class = if EYES_FIX_RANGE.contains(&time) {
         "tw-before-eyes-fix"
     } else {
         ""
@@ -45,7 +46,7 @@ pub(crate) async fn index(
 
     let cache = crate::site::CACHE.read().await;
     let assets = cache
-        .assets(time.0)
+        .assets(time)
         .ok_or_else(|| anyhow!("cache was empty"))?;
 
     let template = Client {
@@ -72,8 +73,8 @@ pub(crate) async fn index(
 #[catch(404)]
 pub(crate) async fn index_default<'a>(req: &'a Request<'_>) -> Result<Response<'a>> {
     let path = req.uri().path();
-    let time = <Option<OffsetTime>>::from_request(req).await.unwrap();
-    let config = <&State<Config>>::from_request(req).await.unwrap();
+    let clock = <Option<AutoPaused>>::from_request(req).await.unwrap();
+    let config = <&State<Arc<Config>>>::from_request(req).await.unwrap();
 
     if [
         "/api",
@@ -90,7 +91,7 @@ pub(crate) async fn index_default<'a>(req: &'a Request<'_>) -> Result<Response<'
             .await
             .map(Response::NotFound)
     } else {
-        index(time, config).await
+        index(clock, config).await
     }
 }
 
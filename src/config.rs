@@ -1,12 +1,20 @@
+use crate::batch::Batcher;
+use crate::fetch::EntityCache;
 use crate::media::ArcVec;
-use crate::stream::StreamCacheValue;
-use crate::time::DateTime;
+use crate::source::archive::Archive;
+use crate::source::record::{RecordMode, Recording};
+use crate::source::{Chronicler, DataSource};
+use crate::stream::{Broadcaster, DiskCache, GameIndexFlight, StreamCache, StreamFlights};
+use crate::time::Duration;
+use crate::upstream::{Client, Limiter, Mirrors};
 use lru::LruCache;
 use rocket::fs::relative;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::borrow::Cow;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
 use tokio::{fs, sync::Mutex};
 use zip::read::ZipArchive;
 
@@ -15,17 +23,79 @@ use zip::read::ZipArchive;
 #[serde(default)]
 pub struct Config {
     pub siesta_mode: bool,
+    // Set if the data source has the complete history of every entity type, including `Game`, so
+    // that schedules can be built from entities rather than `v1/games`.
     pub chronplete: bool,
     pub http_client_gzip: bool,
-    pub chronicler_base_url: String,
-    pub upnuts_base_url: String,
+    // Each of these can be a single base URL or a list of mirrors. Requests go to the first healthy
+    // mirror, or to each healthy mirror in turn if `upstream_round_robin` is set.
+    #[serde(deserialize_with = "one_or_many")]
+    pub chronicler_base_url: Vec<String>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub upnuts_base_url: Vec<String>,
+    pub upstream_round_robin: bool,
+    // If set, Chronicler data is read from a local archive built with `before ingest` instead of
+    // `chronicler_base_url`.
+    pub archive_path: Option<PathBuf>,
+    // If set to "record", every response from Chronicler (or the archive) is also saved to
+    // `record_path`. If set to "replay", only those saved responses are used.
+    pub record_mode: Option<RecordMode>,
+    pub record_path: PathBuf,
+    // Requests to Chronicler and Upnuts time out after this many seconds, and transient failures
+    // (timeouts, connection errors, 5xx and 429 responses) are retried this many times.
+    pub upstream_timeout_secs: u64,
+    pub upstream_retries: u32,
+    // If set, at most this many requests to Chronicler and Upnuts are in flight at once.
+    // Requests someone is waiting on go ahead of background work.
+    pub upstream_concurrency: Option<usize>,
     pub content_security_policy: String,
     pub static_dir: Cow<'static, Path>,
     pub static_zip_path: Option<PathBuf>,
     pub site_cache: bool,
-    // Controls the size of an LRU cache storing stream data. Expect each entry to be about
-    // 5 MB in size.
+    // If set, stream data is kept in an LRU cache of about this many bytes. Neighbouring entries
+    // share most of their data, so each additional entry usually costs far less than the first.
+    pub stream_cache_bytes: Option<usize>,
+    // Replaced by `stream_cache_bytes`. This used to count entries, so there's no safe way to
+    // convert it; Before refuses to start if it's still set.
     pub stream_cache_size: Option<usize>,
+    // If set, stream data is also cached in this directory, which can be shared between instances.
+    // Buckets are only reused by instances with the same `stream_*` bucket settings.
+    // Once the files in it take up more than `stream_disk_cache_bytes`, the oldest are removed.
+    pub stream_disk_cache_path: Option<PathBuf>,
+    pub stream_disk_cache_bytes: u64,
+    // Server-sent event streams send a comment this often, so that proxies don't close them while
+    // nothing is happening. Set to 0 to disable.
+    pub stream_heartbeat_secs: u64,
+    // Stream data is cached in buckets of this many seconds. Each bucket starts from the
+    // `stream_lookback_count` stream versions before it and plays the `stream_lookahead_count`
+    // versions after it. If a component is missing from the versions before it, earlier ones are
+    // fetched (as many again at a time) until it turns up or they're older than
+    // `stream_lookback_horizon_secs`. `fights` are only in the stream during a boss fight, so they
+    // are only looked for back to `stream_fights_horizon_secs`.
+    pub stream_bucket_secs: u64,
+    pub stream_lookback_count: usize,
+    pub stream_lookahead_count: usize,
+    pub stream_lookback_horizon_secs: u64,
+    pub stream_fights_horizon_secs: u64,
+    // Gaps longer than this many seconds between stream versions are filled in with events
+    // reconstructed from game updates and other entities (see src/stream/gaps.rs). Set to 0 to
+    // disable.
+    pub stream_gap_secs: u64,
+    // Stream exports (`/_before/export/stream`) can cover at most this many seconds. Set to 0 to
+    // disable the limit.
+    pub stream_export_max_secs: u64,
+    // Controls the size of an LRU cache storing entity lookups by ID, along with the span of time
+    // each lookup is valid for. Each entry is a distinct query (entity type and IDs), and holds up
+    // to 32 of these spans.
+    pub entity_cache_size: Option<usize>,
+    // Entity lookups follow `nextPage` until they have every matching entity. If set, stop after
+    // this many entities instead.
+    pub entity_fetch_limit: Option<usize>,
+    // Concurrent lookups of single entities of the same type at the same time are batched into one
+    // query, after waiting this many milliseconds for the batch to fill. Set to 0 to disable.
+    pub entity_batch_window_ms: u64,
+    // If set, the replay stream is also served over WebSockets on this port (see src/websocket.rs).
+    pub websocket_port: Option<u16>,
     pub matomo_base_url: Option<String>,
     pub matomo_site_id: Option<i64>,
 
@@ -33,16 +103,41 @@ pub struct Config {
     address: std::net::IpAddr,
 
     #[serde(skip)]
-    pub(crate) client: reqwest::Client,
+    pub(crate) client: Client,
+    #[serde(skip)]
+    pub(crate) upnuts: Mirrors,
+    #[serde(skip)]
+    pub(crate) source: Box<dyn DataSource>,
     #[serde(skip)]
     pub(crate) static_zip: Option<ZipArchive<Cursor<ArcVec>>>,
     #[serde(skip)]
     pub(crate) css_path: Option<String>,
     #[serde(skip)]
-    pub(crate) stream_cache: Option<Mutex<LruCache<DateTime, StreamCacheValue>>>,
+    pub(crate) stream_cache: Option<Mutex<StreamCache>>,
+    #[serde(skip)]
+    pub(crate) stream_disk_cache: Option<DiskCache>,
+    #[serde(skip)]
+    pub(crate) entity_cache: Option<Mutex<EntityCache>>,
+    #[serde(skip)]
+    pub(crate) batcher: Batcher,
+    #[serde(skip)]
+    pub(crate) stream_flights: StreamFlights,
+    #[serde(skip)]
+    pub(crate) broadcaster: Broadcaster,
+    #[serde(skip)]
+    pub(crate) game_index: GameIndexFlight,
 }
 
 impl Config {
+    pub(crate) fn websocket_addr(&self) -> Option<std::net::SocketAddr> {
+        self.websocket_port
+            .map(|port| std::net::SocketAddr::new(self.address, port))
+    }
+
+    pub(crate) fn stream_bucket(&self) -> Duration {
+        Duration::seconds(self.stream_bucket_secs.try_into().unwrap_or(i64::MAX))
+    }
+
     pub(crate) async fn finalize(&mut self) -> anyhow::Result<()> {
         let mut builder = reqwest::Client::builder();
         builder =
@@ -51,7 +146,12 @@ impl Config {
         {
             builder = builder.gzip(self.http_client_gzip);
         }
-        self.client = builder.build()?;
+        self.client = Client {
+            inner: builder.build()?,
+            timeout: Some(StdDuration::from_secs(self.upstream_timeout_secs)),
+            retries: self.upstream_retries,
+            limiter: self.upstream_concurrency.map(|n| Arc::new(Limiter::new(n))),
+        };
 
         let addr = format!(
             "http://{}:{}",
@@ -63,8 +163,36 @@ impl Config {
             self.address,
             self.port,
         );
-        self.chronicler_base_url = self.chronicler_base_url.replace("{addr}", &addr);
-        self.upnuts_base_url = self.upnuts_base_url.replace("{addr}", &addr);
+        for base_url in self
+            .chronicler_base_url
+            .iter_mut()
+            .chain(&mut self.upnuts_base_url)
+        {
+            *base_url = base_url.replace("{addr}", &addr);
+        }
+        anyhow::ensure!(
+            !self.chronicler_base_url.is_empty() && !self.upnuts_base_url.is_empty(),
+            "chronicler_base_url and upnuts_base_url must not be empty"
+        );
+        anyhow::ensure!(
+            self.stream_cache_size.is_none(),
+            "stream_cache_size has been replaced by stream_cache_bytes, which bounds the stream \
+             cache by its size in bytes rather than its number of entries"
+        );
+        self.upnuts = Mirrors::new(&self.upnuts_base_url, self.upstream_round_robin);
+
+        self.source = if let Some(path) = &self.archive_path {
+            Box::new(Archive::open(path).await?)
+        } else {
+            Box::new(Chronicler {
+                client: self.client.clone(),
+                mirrors: Mirrors::new(&self.chronicler_base_url, self.upstream_round_robin),
+            })
+        };
+        if let Some(mode) = self.record_mode {
+            let upstream = std::mem::replace(&mut self.source, Box::new(Chronicler::default()));
+            self.source = Box::new(Recording::new(mode, upstream, &self.record_path).await?);
+        }
 
         self.content_security_policy = self.content_security_policy.replace(
             "{matomo_base_url}",
@@ -92,9 +220,27 @@ impl Config {
             }
         }
 
-        if let Some(stream_cache_size) = self.stream_cache_size {
-            self.stream_cache = Some(Mutex::new(LruCache::new(stream_cache_size)));
+        if let Some(stream_cache_bytes) = self.stream_cache_bytes {
+            self.stream_cache = Some(Mutex::new(StreamCache::new(stream_cache_bytes)));
         }
+        if let Some(path) = &self.stream_disk_cache_path {
+            // everything that changes what goes in a bucket
+            let settings = (
+                self.stream_bucket_secs,
+                self.stream_lookback_count,
+                self.stream_lookahead_count,
+                self.stream_lookback_horizon_secs,
+                self.stream_fights_horizon_secs,
+                self.stream_gap_secs,
+                self.chronplete,
+            );
+            self.stream_disk_cache =
+                Some(DiskCache::new(path, self.stream_disk_cache_bytes, settings).await?);
+        }
+        if let Some(entity_cache_size) = self.entity_cache_size {
+            self.entity_cache = Some(Mutex::new(LruCache::new(entity_cache_size)));
+        }
+        self.batcher.window = StdDuration::from_millis(self.entity_batch_window_ms);
 
         Ok(())
     }
@@ -106,21 +252,68 @@ impl Default for Config {
             siesta_mode: true,
             chronplete: false,
             http_client_gzip: cfg!(feature = "gzip"),
-            chronicler_base_url: "https://api.sibr.dev/chronicler/".into(),
-            upnuts_base_url: "https://api.sibr.dev/upnuts/".into(),
+            chronicler_base_url: vec!["https://api.sibr.dev/chronicler/".into()],
+            upnuts_base_url: vec!["https://api.sibr.dev/upnuts/".into()],
+            upstream_round_robin: false,
+            archive_path: None,
+            record_mode: None,
+            record_path: "recordings".into(),
+            upstream_timeout_secs: 30,
+            upstream_retries: 2,
+            upstream_concurrency: None,
             content_security_policy: "upgrade-insecure-requests; default-src 'self'; script-src 'self' https://platform.twitter.com 'unsafe-inline' 'nonce-{nonce}'; style-src 'self' 'unsafe-inline'; img-src 'self' {matomo_base_url} https://d35iw2jmbg6ut8.cloudfront.net data:; connect-src 'self' {matomo_base_url}; object-src 'none'; frame-src https://platform.twitter.com https://www.youtube.com 'self'; base-uri 'none';".into(),
             static_dir: Path::new(option_env!("STATIC_DIR").unwrap_or(relative!("out"))).into(),
             static_zip_path: None,
             site_cache: true,
+            stream_cache_bytes: None,
             stream_cache_size: None,
+            stream_disk_cache_path: None,
+            stream_disk_cache_bytes: 1 << 30,
+            stream_heartbeat_secs: 15,
+            stream_bucket_secs: 15,
+            stream_lookback_count: 25,
+            stream_lookahead_count: 35,
+            stream_lookback_horizon_secs: 600,
+            stream_fights_horizon_secs: 300,
+            stream_gap_secs: 60,
+            stream_export_max_secs: 86400,
+            entity_cache_size: None,
+            entity_fetch_limit: None,
+            entity_batch_window_ms: 5,
+            websocket_port: None,
             matomo_base_url: None,
             matomo_site_id: None,
             address: std::net::Ipv4Addr::new(127, 0, 0, 1).into(),
             port: 8000,
-            client: reqwest::Client::default(),
+            client: Client::default(),
+            upnuts: Mirrors::default(),
+            source: Box::new(Chronicler::default()),
             static_zip: None,
             css_path: None,
             stream_cache: None,
+            stream_disk_cache: None,
+            entity_cache: None,
+            batcher: Batcher::default(),
+            stream_flights: StreamFlights::default(),
+            game_index: GameIndexFlight::default(),
+            broadcaster: Broadcaster::default(),
         }
     }
 }
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
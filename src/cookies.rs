@@ -20,7 +20,7 @@ impl CookieJarExt for CookieJar<'_> {
             .ok()
             .map(|mut cookie| {
                 // this workaround sucks
-                if T::NAME != "offset_sec" {
+                if T::NAME != "offset_sec" && T::NAME != "playback_rate" {
                     if let Some(time) = OffsetTime::from_cookies(self) {
                         cookie.modify_on_load(time.0);
                     }
@@ -1,4 +1,5 @@
 use crate::offset::OffsetTime;
+use crate::provenance::{DebugMode, Provenance, Source, Transform};
 use crate::time::{datetime, DateTime, Duration};
 use crate::{Config, Result};
 use anyhow::anyhow;
@@ -8,16 +9,19 @@ use rocket::{get, routes, Route, State};
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 pub(crate) fn entity_routes() -> Vec<Route> {
     macro_rules! route {
         ($uri:expr, $ty:expr) => {{
             #[get($uri)]
             pub(crate) async fn entity(
-                config: &State<Config>,
+                config: &State<Arc<Config>>,
                 time: OffsetTime,
+                debug: DebugMode,
             ) -> Result<Option<Json<Box<RawValue>>>> {
-                Ok(config.fetch($ty, None, time.0).await?.next().map(Json))
+                let version = config.fetch_versions($ty, None, time.0).await?.next();
+                Ok(version.map(|v| debug.entity(v)).transpose()?.map(Json))
             }
             routes![entity]
         }};
@@ -27,10 +31,15 @@ pub(crate) fn entity_routes() -> Vec<Route> {
         ($uri:expr, $ty:expr) => {{
             #[get($uri)]
             pub(crate) async fn entity_all(
-                config: &State<Config>,
+                config: &State<Arc<Config>>,
                 time: OffsetTime,
+                debug: DebugMode,
             ) -> Result<Json<Vec<Box<RawValue>>>> {
-                Ok(Json(config.fetch($ty, None, time.0).await?.collect()))
+                Ok(Json(
+                    (config.fetch_versions($ty, None, time.0).await?)
+                        .map(|v| debug.entity(v))
+                        .collect::<anyhow::Result<_>>()?,
+                ))
             }
             routes![entity_all]
         }};
@@ -40,14 +49,16 @@ pub(crate) fn entity_routes() -> Vec<Route> {
         ($uri:expr, $ty:expr) => {{
             #[get($uri)]
             pub(crate) async fn entity_id(
-                config: &State<Config>,
+                config: &State<Arc<Config>>,
                 id: String,
                 time: OffsetTime,
+                debug: DebugMode,
             ) -> Result<Option<Json<Box<RawValue>>>> {
                 if id.is_empty() {
                     Ok(None)
                 } else {
-                    Ok(config.fetch($ty, Some(id), time.0).await?.next().map(Json))
+                    let version = config.fetch_versions($ty, Some(id), time.0).await?.next();
+                    Ok(version.map(|v| debug.entity(v)).transpose()?.map(Json))
                 }
             }
             routes![entity_id]
@@ -82,31 +93,43 @@ pub(crate) fn entity_routes() -> Vec<Route> {
 
 #[get("/database/gameById/<id>")]
 pub(crate) async fn game_by_id(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     id: String,
     time: OffsetTime,
+    debug: DebugMode,
 ) -> Result<Option<Json<Box<RawValue>>>> {
-    Ok(config.fetch_game(id, time.0).await?.map(Json))
+    Ok(match config.fetch_game_update(id, time.0).await? {
+        Some((timestamp, data)) => Some(Json(
+            debug.annotate(data, &Provenance::new(Source::Chronicler, Some(timestamp)))?,
+        )),
+        None => None,
+    })
 }
 
 #[get("/database/items?<ids>")]
 pub(crate) async fn items(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     ids: String,
     time: OffsetTime,
+    debug: DebugMode,
 ) -> Result<Json<Vec<Box<RawValue>>>> {
     // Workaround for Chronicler not picking up items as soon as prize matches start: if requesting
     // a single item, fetch as normal, and if the response is empty, try again with an `at` of one
     // hour later.
     let data = config
-        .fetch("Item", Some(ids.clone()), time.0)
+        .fetch_versions("Item", Some(ids.clone()), time.0)
         .await?
-        .collect::<Vec<_>>();
+        .map(|v| debug.entity(v))
+        .collect::<anyhow::Result<Vec<_>>>()?;
     Ok(Json(if !ids.contains(',') && data.is_empty() {
         config
-            .fetch("Item", Some(ids), time.0 + Duration::hours(1))
+            .fetch_versions("Item", Some(ids), time.0 + Duration::hours(1))
             .await?
-            .collect()
+            .map(|v| {
+                let provenance = Provenance::chronicler(&v).with(Transform::LaterVersion);
+                debug.annotate(v.data, &provenance)
+            })
+            .collect::<anyhow::Result<_>>()?
     } else {
         data
     }))
@@ -147,7 +170,7 @@ pub(crate) enum PreviousChamp {
 
 #[get("/database/getPreviousChamp")]
 pub(crate) async fn get_previous_champ(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     time: OffsetTime,
 ) -> Result<Json<PreviousChamp>> {
     #[derive(Deserialize)]
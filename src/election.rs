@@ -8,6 +8,7 @@ use serde::Deserialize;
 use serde_json::value::RawValue;
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 lazy_static::lazy_static! {
     static ref OFFSEASON_RECAP: Vec<&'static RawValue> =
@@ -31,7 +32,7 @@ fn check_data() {
 
 #[get("/database/offseasonRecap?<season>")]
 pub(crate) async fn offseason_recap(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     season: i64,
     time: OffsetTime,
 ) -> Result<Option<Json<Cow<'static, RawValue>>>> {
@@ -92,7 +93,7 @@ async fn locally_patched(
 
 #[get("/database/bonusResults?<ids>")]
 pub(crate) async fn bonus_results(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     ids: &str,
     time: OffsetTime,
 ) -> Result<Json<Vec<Cow<'static, RawValue>>>> {
@@ -101,7 +102,7 @@ pub(crate) async fn bonus_results(
 
 #[get("/database/decreeResults?<ids>")]
 pub(crate) async fn decree_results(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     ids: &str,
     time: OffsetTime,
 ) -> Result<Json<Vec<Cow<'static, RawValue>>>> {
@@ -110,7 +111,7 @@ pub(crate) async fn decree_results(
 
 #[get("/database/eventResults?<ids>")]
 pub(crate) async fn event_results(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     ids: &str,
     time: OffsetTime,
 ) -> Result<Json<Vec<Cow<'static, RawValue>>>> {
@@ -1,25 +1,81 @@
-use crate::offset::{Offset, OffsetTime};
+use crate::metrics::SseGuard;
+use crate::offset::AutoPaused;
+use crate::provenance::{sidecar, DebugMode};
+use crate::stream::patch::StreamFormat;
 use crate::stream::{self, Item};
 use crate::time::DateTime;
 use crate::{Config, Result};
 use rocket::futures::StreamExt;
+use rocket::request::{FromRequest, Outcome, Request};
 use rocket::response::stream::{Event, EventStream};
-use rocket::{get, routes, Route, Shutdown, State};
-use serde::Serialize;
+use rocket::{async_trait, get, routes, Route, Shutdown, State};
+use serde_json::json;
+use serde_json::value::RawValue;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+/// The `Last-Event-ID` header an `EventSource` sends when it reconnects. Each event's id is the
+/// perceived time it brings the client up to (see [`Item::time`]).
+pub(crate) struct LastEventId(Option<DateTime>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<LastEventId, Infallible> {
+        let id = req.headers().get_one("Last-Event-ID");
+        Outcome::Success(LastEventId(id.and_then(|id| id.parse().ok())))
+    }
+}
+
+fn heartbeat(config: &Config) -> Option<StdDuration> {
+    let secs = config.stream_heartbeat_secs;
+    (secs > 0).then(|| StdDuration::from_secs(secs))
+}
 
 #[get("/events/streamData")]
 pub(crate) async fn stream_data(
-    config: &State<Config>,
-    time: OffsetTime,
-    offset: Offset,
+    config: &State<Arc<Config>>,
+    clock: AutoPaused,
+    format: StreamFormat,
+    last_event_id: LastEventId,
+    debug: DebugMode,
     shutdown: Shutdown,
 ) -> Result<EventStream![]> {
-    let mut stream = Box::pin(stream::start(config, time, offset, shutdown.clone()).await?);
+    // A JSON Patch stream has to start with a keyframe of everything, which is no different from
+    // starting over, so those aren't resumed.
+    let resume = last_event_id.0.filter(|_| format == StreamFormat::Full);
+    let mut stream = Box::pin(stream::start(config, clock.0, resume, shutdown.clone()).await?);
+    let mut patcher = format.patcher();
     Ok(EventStream! {
+        let _guard = SseGuard::new();
         while let Some(item) = stream.next().await {
-            yield Event::json(&item);
+            let id = item.time().to_string();
+            match &mut patcher {
+                Some(patcher) => match patcher.next(&item) {
+                    Ok(mut message) => {
+                        if let (DebugMode(true), Some(message)) = (debug, message.as_object_mut()) {
+                            message.insert("_before".into(), json!(item.sources()));
+                        }
+                        yield Event::json(&message).id(id);
+                    }
+                    Err(err) => log::warn!("failed to diff stream item: {err}"),
+                },
+                None => match item.to_json() {
+                    Ok(json) => {
+                        let annotated = match debug {
+                            DebugMode(true) => sidecar(&json, &item.sources()).ok().flatten(),
+                            DebugMode(false) => None,
+                        };
+                        yield Event::data(annotated.unwrap_or_else(|| json.to_string())).id(id);
+                    }
+                    Err(err) => log::warn!("failed to serialize stream item: {err}"),
+                },
+            }
         }
-    })
+    }
+    .heartbeat(heartbeat(config)))
 }
 
 // For part of Season 4, the frontend used separate endpoints for the different components of the
@@ -31,17 +87,17 @@ pub(crate) fn extra_season_4_routes() -> Vec<Route> {
         ($x:ident, $uri:expr) => {{
             #[get($uri)]
             pub(crate) async fn stream_individual(
-                config: &State<Config>,
-                time: OffsetTime,
-                offset: Offset,
+                config: &State<Arc<Config>>,
+                clock: AutoPaused,
                 shutdown: Shutdown,
             ) -> Result<EventStream![]> {
                 let mut stream =
-                    Box::pin(stream::start(config, time, offset, shutdown.clone()).await?);
+                    Box::pin(stream::start(config, clock.0, None, shutdown.clone()).await?);
                 Ok(EventStream! {
+                    let _guard = SseGuard::new();
                     while let Some(item) = stream.next().await {
                         match item {
-                            Item::Start(v) => yield with_last_update(&v.$x),
+                            Item::Start(_, v) => yield with_last_update(&v.$x),
                             Item::Update(v) => {
                                 if let Some(v) = &v.data.value.$x {
                                     yield with_last_update(v);
@@ -49,7 +105,8 @@ pub(crate) fn extra_season_4_routes() -> Vec<Route> {
                             }
                         }
                     }
-                })
+                }
+                .heartbeat(heartbeat(config)))
             }
 
             routes![stream_individual]
@@ -64,16 +121,63 @@ pub(crate) fn extra_season_4_routes() -> Vec<Route> {
     .concat()
 }
 
-fn with_last_update<T: Serialize>(data: &T) -> Event {
-    Event::data(
-        serde_json::to_string(data)
-            .map(|s| {
-                format!(
-                    "{{\"value\":{},\"lastUpdateTime\":{}}}}}",
-                    &s[0..s.len() - 1],
-                    DateTime::now().unix_timestamp_millis()
-                )
-            })
-            .unwrap_or_default(),
+fn with_last_update(data: &RawValue) -> Event {
+    let s = data.get();
+    Event::data(format!(
+        "{{\"value\":{},\"lastUpdateTime\":{}}}}}",
+        &s[0..s.len() - 1],
+        DateTime::now().unix_timestamp_millis()
+    ))
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn resumed_patch_stream_starts_with_keyframe() {
+    use crate::cookies::AsCookie;
+    use crate::offset::Offset;
+    use crate::source::fixture::{Fixture, STREAM_START};
+    use crate::time::Duration;
+    use rocket::http::{Cookie, Header};
+    use rocket::local::asynchronous::Client;
+    use tokio::io::AsyncReadExt;
+
+    let mut config = Config::default();
+    config.source = Box::new(Fixture::stream());
+    let client = Client::tracked(
+        rocket::build()
+            .manage(Arc::new(config))
+            .mount("/", routes![stream_data]),
     )
+    .await
+    .unwrap();
+    let at = |secs| STREAM_START + Duration::seconds(secs);
+    let mut response = client
+        .get("/events/streamData")
+        .cookie(Cookie::new(
+            Offset::NAME,
+            Offset(DateTime::now() - at(40)).to_string(),
+        ))
+        .header(Header::new("X-Before-Stream-Format", "json-patch"))
+        .header(Header::new("Last-Event-ID", at(22).to_string()))
+        .dispatch()
+        .await;
+
+    let mut body = Vec::new();
+    while !body.ends_with(b"\n\n") {
+        let mut buf = [0; 1024];
+        let len = response.read(&mut buf).await.unwrap();
+        assert!(len > 0);
+        body.extend_from_slice(&buf[..len]);
+    }
+    let body = String::from_utf8(body).unwrap();
+    let data = (body.lines())
+        .find_map(|line| line.strip_prefix("data:"))
+        .unwrap();
+    let message: serde_json::Value = serde_json::from_str(data).unwrap();
+    let value = message["value"].as_object().unwrap();
+    for component in ["games", "leagues", "temporal"] {
+        assert!(value.contains_key(component), "{component} missing");
+    }
 }
@@ -0,0 +1,94 @@
+//! Bulk export of the stream, for archiving and analysing replays offline.
+//!
+//! `/_before/export/stream?from=&to=` returns newline-delimited JSON: the first line is the state
+//! of every component at `from`, and each following line is an update, up to and including `to`.
+//! Each line is what `/events/streamData` would have sent at that point, with a `time` key added
+//! (and a `_before` key, if requested with `X-Before-Debug`). Nothing waits for real time to pass,
+//! so the response is only as slow as loading the stream. Windows longer than
+//! `stream_export_max_secs` are rejected.
+
+use crate::http::BadRequest;
+use crate::provenance::{sidecar, DebugMode};
+use crate::stream::{self, Item};
+use crate::time::{DateTime, Duration};
+use crate::{Config, Result};
+use rocket::futures::StreamExt;
+use rocket::http::ContentType;
+use rocket::response::stream::TextStream;
+use rocket::{get, State};
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[get("/_before/export/stream?<from>&<to>")]
+pub(crate) async fn export_stream(
+    config: &State<Arc<Config>>,
+    from: &str,
+    to: &str,
+    debug: DebugMode,
+) -> Result<(ContentType, TextStream![String])> {
+    let parse = |name: &str, value: &str| {
+        DateTime::from_str(value).map_err(|err| BadRequest(format!("invalid `{name}`: {err}")))
+    };
+    let from = parse("from", from)?;
+    let to = parse("to", to)?;
+    if from > to {
+        return Err(BadRequest("`from` must not be after `to`".into()).into());
+    }
+    let max = config.stream_export_max_secs;
+    if max > 0 && to - from > Duration::seconds(max.try_into().unwrap_or(i64::MAX)) {
+        return Err(BadRequest(format!("exports can cover at most {max} seconds")).into());
+    }
+
+    let mut stream = Box::pin(stream::export(config, from, to).await?);
+    Ok((
+        ContentType::new("application", "x-ndjson"),
+        TextStream! {
+            while let Some(item) = stream.next().await {
+                match line(&item, debug) {
+                    Ok(line) => yield line,
+                    Err(err) => log::warn!("failed to serialize stream item: {err}"),
+                }
+            }
+        },
+    ))
+}
+
+fn line(item: &Item, debug: DebugMode) -> serde_json::Result<String> {
+    let json = item.to_json()?;
+    let json = match debug {
+        DebugMode(true) => sidecar(&json, &item.sources())?.unwrap_or_else(|| json.to_string()),
+        DebugMode(false) => json.to_string(),
+    };
+    // stream items are always objects
+    Ok(format!(
+        "{{\"time\":{},{}\n",
+        serde_json::to_string(&item.time())?,
+        json.trim_start().trim_start_matches('{'),
+    ))
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn bad_requests() {
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+
+    let rocket = rocket::build()
+        .manage(Arc::new(Config::default()))
+        .mount("/", rocket::routes![export_stream]);
+    let client = Client::tracked(rocket).await.unwrap();
+    for uri in [
+        "/_before/export/stream?from=yesterday&to=2021-03-01T00:00:00Z",
+        "/_before/export/stream?from=2021-03-01T00:00:00Z&to=2021-03-01T00:00:00",
+        "/_before/export/stream?from=2021-03-01T01:00:00Z&to=2021-03-01T00:00:00Z",
+        "/_before/export/stream?from=2021-03-01T00:00:00Z&to=2021-03-03T00:00:00Z",
+    ] {
+        assert_eq!(
+            client.get(uri).dispatch().await.status(),
+            Status::BadRequest,
+            "{uri}"
+        );
+    }
+}
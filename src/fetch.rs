@@ -2,9 +2,28 @@ use crate::chronicler::{Order, RequestBuilder, Version};
 use crate::time::DateTime;
 use crate::Config;
 use anyhow::Result;
+use lru::LruCache;
+use rocket::futures::{StreamExt, TryStreamExt};
 use serde::Deserialize;
 use serde_json::value::RawValue;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// The number of validity intervals kept for each distinct query in the entity cache.
+const ENTITY_CACHE_INTERVALS: usize = 32;
+
+type EntityKey = (&'static str, Option<String>);
+
+/// Caches `v2/entities` lookups by entity type and IDs. Each response is valid for any `at` between
+/// the latest `valid_from` and the earliest `valid_to` of the versions in it, and is keyed by the
+/// start of that interval.
+pub(crate) type EntityCache = LruCache<EntityKey, BTreeMap<DateTime, CachedEntities>>;
+
+#[derive(Debug)]
+pub(crate) struct CachedEntities {
+    valid_to: DateTime,
+    versions: Arc<Vec<Version<Box<RawValue>>>>,
+}
 
 impl Config {
     async fn fetch_inner<T>(
@@ -16,12 +35,86 @@ impl Config {
     where
         for<'de> T: Deserialize<'de>,
     {
-        let mut builder = RequestBuilder::v2("entities").ty(ty).at(time);
-        if let Some(ids) = ids {
-            builder = builder.id(ids);
-        }
+        let batched =
+            self.batcher.is_enabled() && ids.as_deref().map_or(false, |id| !id.contains(','));
+        // An entity created partway through the interval a type-wide query's response is valid
+        // for wouldn't end any version in it, so only lookups by ID are cached.
+        let cache = match &self.entity_cache {
+            Some(cache) if ids.is_some() => cache,
+            _ if batched => {
+                return deserialize_versions(&self.fetch_raw(ty, ids, time).await?);
+            }
+            _ => return Ok(fetch_entities(self, ty, ids, time).await?.into_iter()),
+        };
+
+        let key = (ty, ids);
+        let cached = cache.lock().await.get(&key).and_then(|intervals| {
+            intervals
+                .range(..=time)
+                .next_back()
+                .filter(|(_, cached)| time < cached.valid_to)
+                .map(|(_, cached)| cached.versions.clone())
+        });
+        let versions = if let Some(versions) = cached {
+            versions
+        } else {
+            let fetched_at = DateTime::now();
+            let versions = Arc::new(self.fetch_raw(ty, key.1.clone(), time).await?);
+            // For the same reason, only cache responses with every requested ID (an empty response
+            // doesn't tell us when its interval starts anyway).
+            let complete = key.1.as_deref().map_or(false, |ids| {
+                ids.split(',')
+                    .all(|id| versions.iter().any(|v| v.entity_id == id))
+            });
+            if let (true, Some(valid_from)) =
+                (complete, versions.iter().map(|v| v.valid_from).max())
+            {
+                // A version without a `valid_to` is current, which we only know to be true as of
+                // right now
+                let valid_to = versions
+                    .iter()
+                    .map(|v| v.valid_to.unwrap_or(fetched_at))
+                    .min()
+                    .unwrap_or(fetched_at);
+
+                let mut guard = cache.lock().await;
+                if !guard.contains(&key) {
+                    guard.put(key.clone(), BTreeMap::new());
+                }
+                if let Some(intervals) = guard.get_mut(&key) {
+                    if intervals.len() >= ENTITY_CACHE_INTERVALS {
+                        if let Some(first) = intervals.keys().next().copied() {
+                            intervals.remove(&first);
+                        }
+                    }
+                    intervals.insert(
+                        valid_from,
+                        CachedEntities {
+                            valid_to,
+                            versions: versions.clone(),
+                        },
+                    );
+                }
+            }
+            versions
+        };
+
+        deserialize_versions(&versions)
+    }
 
-        Ok(builder.json(self).await?.items.into_iter())
+    /// Fetches entities without deserializing them, batching single-ID lookups if enabled.
+    async fn fetch_raw(
+        &self,
+        ty: &'static str,
+        ids: Option<String>,
+        time: DateTime,
+    ) -> Result<Vec<Version<Box<RawValue>>>> {
+        match ids {
+            Some(id) if self.batcher.is_enabled() && !id.contains(',') => {
+                self.batcher.fetch(self, ty, id, time).await
+            }
+            ids => fetch_entities(self, ty, ids, time).await,
+        }
     }
 
     pub(crate) async fn fetch<T>(
@@ -39,6 +132,19 @@ impl Config {
             .map(|version| version.data))
     }
 
+    /// Like [`Config::fetch`], but keeps each entity's version metadata.
+    pub(crate) async fn fetch_versions<T>(
+        &self,
+        ty: &'static str,
+        ids: Option<String>,
+        time: DateTime,
+    ) -> Result<impl Iterator<Item = Version<T>>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        self.fetch_inner(ty, ids, time).await
+    }
+
     pub(crate) async fn fetch_map<T>(
         &self,
         ty: &'static str,
@@ -60,8 +166,21 @@ impl Config {
         id: String,
         time: DateTime,
     ) -> Result<Option<Box<RawValue>>> {
+        Ok(self
+            .fetch_game_update(id, time)
+            .await?
+            .map(|(_, data)| data))
+    }
+
+    /// Fetches the latest update to a game before `time`, along with when it was collected.
+    pub(crate) async fn fetch_game_update(
+        &self,
+        id: String,
+        time: DateTime,
+    ) -> Result<Option<(DateTime, Box<RawValue>)>> {
         #[derive(Deserialize)]
         struct Game {
+            timestamp: DateTime,
             data: Box<RawValue>,
         }
 
@@ -78,7 +197,151 @@ impl Config {
                 .data
                 .into_iter()
                 .next()
-                .map(|item: Game| item.data)
+                .map(|item: Game| (item.timestamp, item.data))
+        })
+    }
+}
+
+fn deserialize_versions<T>(
+    versions: &[Version<Box<RawValue>>],
+) -> Result<std::vec::IntoIter<Version<T>>>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    Ok(versions
+        .iter()
+        .map(|v| {
+            Ok(Version {
+                valid_from: v.valid_from,
+                valid_to: v.valid_to,
+                entity_id: v.entity_id.clone(),
+                data: serde_json::from_str(v.data.get())?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter())
+}
+
+pub(crate) async fn fetch_entities<T>(
+    config: &Config,
+    ty: &'static str,
+    ids: Option<String>,
+    time: DateTime,
+) -> Result<Vec<Version<T>>>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    let mut builder = RequestBuilder::v2("entities").ty(ty).at(time);
+    if let Some(ids) = ids {
+        builder = builder.id(ids);
+    }
+
+    let limit = config.entity_fetch_limit.unwrap_or(usize::MAX);
+    let versions: Vec<_> = builder.paged_json(config).take(limit).try_collect().await?;
+    if versions.len() == limit {
+        log::warn!("{ty} entities query at {time} truncated to {limit} items");
+    }
+    Ok(versions)
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn entity_cache_intervals() {
+    use crate::source::fixture::Fixture;
+    use crate::time::datetime;
+    use crate::Config;
+    use lru::LruCache;
+    use serde_json::json;
+    use tokio::sync::Mutex;
+
+    let fixture = Fixture::new(|_| {
+        json!({
+            "items": [{
+                "entityId": "d",
+                "validFrom": "2021-03-01T00:00:00Z",
+                "validTo": "2021-03-02T00:00:00Z",
+                "data": "division",
+            }],
         })
+    });
+    let queries = fixture.queries();
+    let requests = || queries.lock().unwrap().len();
+    let mut config = Config::default();
+    config.source = Box::new(fixture);
+    config.entity_cache = Some(Mutex::new(LruCache::new(1)));
+
+    for time in [
+        datetime!(2021-03-01 01:00:00 UTC),
+        datetime!(2021-03-01 23:59:59 UTC),
+        datetime!(2021-03-01 00:00:00 UTC),
+    ] {
+        let division: String = config
+            .fetch("Division", Some("d".into()), time)
+            .await
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(division, "division");
+    }
+    assert_eq!(requests(), 1);
+
+    let next_day: Vec<String> = config
+        .fetch(
+            "Division",
+            Some("d".into()),
+            datetime!(2021-03-02 00:00:00 UTC),
+        )
+        .await
+        .unwrap()
+        .collect();
+    assert_eq!(next_day, ["division"]);
+    assert_eq!(requests(), 2);
+
+    // a division could be created at any point, so type-wide queries aren't cached
+    for _ in 0..2 {
+        let divisions: Vec<String> = config
+            .fetch("Division", None, datetime!(2021-03-01 01:00:00 UTC))
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(divisions, ["division"]);
     }
+    assert_eq!(requests(), 4);
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn fetch_all_pages() {
+    use crate::source::fixture::Fixture;
+    use crate::time::datetime;
+    use crate::Config;
+    use serde_json::json;
+
+    let mut config = Config::default();
+    config.source = Box::new(Fixture::new(|query| {
+        let (next_page, id) = match query.page.as_deref() {
+            None => (Some("next"), "a"),
+            Some(_) => (None, "b"),
+        };
+        json!({
+            "nextPage": next_page,
+            "items": [{ "entityId": id, "validFrom": "2021-03-01T00:00:00Z", "data": id }],
+        })
+    }));
+    let players: Vec<String> = config
+        .fetch("Player", None, datetime!(2021-03-01 01:00:00 UTC))
+        .await
+        .unwrap()
+        .collect();
+    assert_eq!(players, ["a", "b"]);
+
+    config.entity_fetch_limit = Some(1);
+    let players: Vec<String> = config
+        .fetch("Player", None, datetime!(2021-03-01 01:00:00 UTC))
+        .await
+        .unwrap()
+        .collect();
+    assert_eq!(players, ["a"]);
 }
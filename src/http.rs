@@ -1,49 +1,116 @@
+use crate::source::Download;
+use crate::upstream::UpstreamError;
 use rocket::futures::TryStreamExt;
 use rocket::http::hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
 use rocket::http::{ContentType, Header, Status};
 use rocket::request::Request;
 use rocket::response::{Responder, Response, Result};
 use std::collections::hash_map::DefaultHasher;
+use std::fmt::{self, Display};
 use std::hash::{Hash, Hasher};
+use std::io::Cursor;
 use std::str::FromStr;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 
 #[derive(Debug)]
-pub(crate) struct Proxy(pub(crate) reqwest::Response);
+pub(crate) struct Proxy(pub(crate) Download);
 
 impl<'r> Responder<'r, 'static> for Proxy {
     fn respond_to(self, _request: &'r Request<'_>) -> Result<'static> {
         let mut builder = Response::build();
-        builder.status(Status::new(self.0.status().as_u16()));
-        if let Some(ct) = self
-            .0
-            .headers()
-            .get(CONTENT_TYPE)
-            .and_then(|s| s.to_str().ok())
-            .and_then(|s| ContentType::from_str(s).ok())
-        {
-            builder.header(ct);
+        match self.0 {
+            Download::Response(response) => {
+                builder.status(Status::new(response.status().as_u16()));
+                if let Some(ct) = response
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|s| s.to_str().ok())
+                    .and_then(|s| ContentType::from_str(s).ok())
+                {
+                    builder.header(ct);
+                }
+                if let Some(len) = response
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|s| s.to_str().ok())
+                {
+                    builder.header(Header::new(CONTENT_LENGTH.as_str(), len.to_owned()));
+                }
+                builder.streamed_body(
+                    response
+                        .bytes_stream()
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+                        .into_async_read()
+                        .compat(),
+                );
+            }
+            Download::Saved { content_type, body } => {
+                if let Some(ct) = content_type.and_then(|s| ContentType::from_str(&s).ok()) {
+                    builder.header(ct);
+                }
+                builder.sized_body(body.len(), Cursor::new(body));
+            }
         }
-        if let Some(len) = self
-            .0
-            .headers()
-            .get(CONTENT_LENGTH)
-            .and_then(|s| s.to_str().ok())
-        {
-            builder.header(Header::new(CONTENT_LENGTH.as_str(), len.to_owned()));
+        builder.ok()
+    }
+}
+
+/// The error type for our routes.
+///
+/// Upstream failures become a 502 or 504 and [`BadRequest`]s become a 400 (with a JSON body for API
+/// routes, so the client can show something sensible); anything else is logged and becomes a 500.
+#[derive(Debug)]
+pub(crate) struct Error(anyhow::Error);
+
+impl<E: Into<anyhow::Error>> From<E> for Error {
+    fn from(err: E) -> Error {
+        Error(err.into())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, request: &'r Request<'_>) -> Result<'static> {
+        let status = self.0.chain().find_map(|err| {
+            if let Some(upstream) = err.downcast_ref::<UpstreamError>() {
+                log::warn!("{:#}", self.0);
+                Some((upstream.status(), upstream.to_string()))
+            } else {
+                let bad_request = err.downcast_ref::<BadRequest>()?;
+                Some((Status::BadRequest, bad_request.to_string()))
+            }
+        });
+        if let Some((status, message)) = status {
+            let path = request.uri().path();
+            if path.starts_with("/api") || path.starts_with("/database") {
+                let body = serde_json::json!({ "error": message }).to_string();
+                Response::build()
+                    .status(status)
+                    .header(ContentType::JSON)
+                    .sized_body(body.len(), Cursor::new(body))
+                    .ok()
+            } else {
+                Err(status)
+            }
+        } else {
+            rocket::response::Debug(self.0).respond_to(request)
         }
-        builder
-            .streamed_body(
-                self.0
-                    .bytes_stream()
-                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
-                    .into_async_read()
-                    .compat(),
-            )
-            .ok()
     }
 }
 
+/// A request we can't answer because of its parameters, such as an out-of-range value.
+#[derive(Debug)]
+pub(crate) struct BadRequest(pub(crate) String);
+
+impl Display for BadRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BadRequest {}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
 #[derive(Debug)]
 pub(crate) struct ETag(u64);
 
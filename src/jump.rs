@@ -1,19 +1,21 @@
 use crate::chronicler::RequestBuilder;
-use crate::cookies::CookieJarExt;
+use crate::cookies::{AsCookie, CookieJarExt};
 use crate::favorite_team::FavoriteTeam;
-use crate::offset::Offset;
+use crate::http::BadRequest;
+use crate::offset::{AutoPause, Clock, Offset, Playback, MAX_RATE};
 use crate::redirect::Redirect;
 use crate::time::{DateTime, Duration};
 use crate::{Config, Result};
 use rocket::form::FromForm;
-use rocket::http::CookieJar;
+use rocket::http::{Cookie, CookieJar};
 use rocket::{get, State};
 use serde::Deserialize;
 use std::str::FromStr;
+use std::sync::Arc;
 
 #[get("/_before/jump?<redirect>&<start>&<team>&<jump_time..>")]
 pub(crate) async fn jump(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     cookies: &CookieJar<'_>,
     redirect: Option<String>,
     start: Option<&str>,
@@ -26,14 +28,21 @@ pub(crate) async fn jump(
         cookies.store(&FavoriteTeam::random());
     }
 
-    let start_offset = match start {
-        Some(start) => DateTime::from_str(start).map_err(anyhow::Error::from)? - DateTime::now(),
-        None => Duration::ZERO,
+    let start = match start {
+        Some(start) => DateTime::from_str(start).map_err(anyhow::Error::from)?,
+        None => DateTime::now(),
     };
-    Ok(jump_time.to_time(config).await?.map(|time| {
-        cookies.store(&Offset(DateTime::now() + start_offset - time));
-        Redirect(redirect)
-    }))
+    let clock = Clock::from_cookies(cookies).unwrap_or(Clock {
+        offset: Offset(Duration::ZERO),
+        playback: Playback::default(),
+    });
+    Ok(match jump_time.to_time(config).await? {
+        Some(time) => {
+            clock.jump(time, start)?.store(cookies);
+            Some(Redirect(redirect))
+        }
+        None => None,
+    })
 }
 
 #[derive(Debug, FromForm)]
@@ -83,11 +92,70 @@ impl<'a> JumpTime<'a> {
 #[get("/_before/relative?<redirect>&<duration..>")]
 pub(crate) fn relative(
     cookies: &CookieJar<'_>,
-    offset: Offset,
+    clock: Clock,
     redirect: Option<String>,
     duration: FormDuration,
+) -> Result<Redirect> {
+    let now = DateTime::now();
+    clock
+        .jump(clock.time_at(now) + duration.into_duration(), now)?
+        .store(cookies);
+    Ok(Redirect(redirect))
+}
+
+#[get("/_before/rate?<redirect>&<rate>")]
+pub(crate) fn rate(
+    cookies: &CookieJar<'_>,
+    clock: Clock,
+    redirect: Option<String>,
+    rate: f64,
+) -> Result<Redirect> {
+    if !(rate > 0.0 && rate <= MAX_RATE) {
+        return Err(anyhow::Error::new(BadRequest(format!(
+            "rate must be greater than 0 and at most {MAX_RATE}"
+        )))
+        .into());
+    }
+    clock.with_rate(rate)?.store(cookies);
+    Ok(Redirect(redirect))
+}
+
+#[get("/_before/pause?<redirect>")]
+pub(crate) fn pause(
+    cookies: &CookieJar<'_>,
+    clock: Clock,
+    redirect: Option<String>,
+) -> Result<Redirect> {
+    clock.with_paused(true)?.store(cookies);
+    Ok(Redirect(redirect))
+}
+
+#[get("/_before/resume?<redirect>")]
+pub(crate) fn resume(
+    cookies: &CookieJar<'_>,
+    clock: Clock,
+    redirect: Option<String>,
+) -> Result<Redirect> {
+    clock.with_paused(false)?.store(cookies);
+    Ok(Redirect(redirect))
+}
+
+#[get("/_before/auto_pause?<enabled>&<redirect>")]
+pub(crate) fn auto_pause(
+    cookies: &CookieJar<'_>,
+    clock: Clock,
+    enabled: bool,
+    redirect: Option<String>,
 ) -> Redirect {
-    cookies.store(&Offset(offset.0 - duration.into_duration()));
+    if enabled {
+        let now = DateTime::now();
+        cookies.store(&AutoPause {
+            time: clock.time_at(now),
+            wall: now,
+        });
+    } else {
+        cookies.remove(Cookie::named(AutoPause::NAME));
+    }
     Redirect(redirect)
 }
 
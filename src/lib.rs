@@ -3,6 +3,7 @@
 #![allow(clippy::no_effect_underscore_binding)]
 
 mod api;
+mod batch;
 mod bet;
 mod chronicler;
 mod client;
@@ -11,6 +12,7 @@ mod cookies;
 mod database;
 mod election;
 mod events;
+mod export;
 mod favorite_team;
 mod feed;
 mod fetch;
@@ -18,21 +20,27 @@ mod http;
 mod idol;
 mod jump;
 mod media;
+mod metrics;
 mod offset;
 mod offsite;
 mod players;
+mod provenance;
 mod redirect;
 mod settings;
 mod site;
 mod snacks;
 mod socket_io;
+mod source;
 mod squirrels;
 mod stream;
 mod tarot;
 mod time;
+mod upstream;
 mod user;
+mod websocket;
 
 pub use crate::config::Config;
+pub use crate::source::archive::ingest;
 
 use crate::time::{datetime, DateTime};
 use rocket::fairing::AdHoc;
@@ -41,11 +49,12 @@ use rocket::http::CookieJar;
 use rocket::response::Redirect;
 use rocket::tokio;
 use rocket::{catchers, get, routes, uri, Build, Rocket};
+use std::sync::Arc;
 use std::time::Duration as StdDuration;
 
 const EXPANSION: DateTime = datetime!(2021-03-01 04:10:00 UTC);
 
-type Result<T> = std::result::Result<T, rocket::response::Debug<anyhow::Error>>;
+type Result<T> = std::result::Result<T, crate::http::Error>;
 
 #[get("/auth/logout")]
 fn reset(cookies: &CookieJar<'_>) -> Redirect {
@@ -77,12 +86,19 @@ pub async fn build(figment: &Figment) -> anyhow::Result<Rocket<Build>> {
 
     let mut config: Config = figment.extract()?;
     config.finalize().await?;
+    let config = Arc::new(config);
 
     Ok(rocket
-        .manage(config)
+        .manage(config.clone())
         .attach(AdHoc::on_liftoff("Before background tasks", |_rocket| {
             Box::pin(background_tasks())
         }))
+        .attach(AdHoc::on_liftoff("WebSocket listener", move |rocket| {
+            if let Some(addr) = config.websocket_addr() {
+                tokio::spawn(websocket::serve(config, addr, rocket.shutdown()));
+            }
+            Box::pin(rocket::futures::future::ready(()))
+        }))
         .attach(AdHoc::on_response(
             "If-None-Match middleware",
             |request, response| {
@@ -90,6 +106,7 @@ pub async fn build(figment: &Figment) -> anyhow::Result<Rocket<Build>> {
                 Box::pin(rocket::futures::future::ready(()))
             },
         ))
+        .attach(metrics::RequestMetrics)
         .mount("/", database::entity_routes())
         .mount("/", events::extra_season_4_routes())
         .mount(
@@ -107,15 +124,21 @@ pub async fn build(figment: &Figment) -> anyhow::Result<Rocket<Build>> {
                 election::event_results,
                 election::offseason_recap,
                 events::stream_data,
+                export::export_stream,
                 favorite_team::buy_flute,
                 favorite_team::update_favorite_team,
                 feed::feed,
                 feed::feedbyphase,
                 idol::choose_idol,
                 jump::jump,
+                jump::auto_pause,
+                jump::pause,
+                jump::rate,
                 jump::relative,
+                jump::resume,
                 media::static_media,
                 media::static_root,
+                metrics::metrics,
                 offsite::offsite,
                 players::player_names_ids,
                 players::players,
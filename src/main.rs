@@ -1,5 +1,10 @@
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("ingest") {
+        return before::ingest(&args[1..]);
+    }
+
     before::build(&rocket::Config::figment())
         .await?
         .launch()
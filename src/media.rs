@@ -50,7 +50,7 @@ pub(crate) enum Static {
 }
 
 async fn fetch_static(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     path: &Path,
     range: Option<Range<'_>>,
 ) -> anyhow::Result<Option<Static>> {
@@ -120,7 +120,10 @@ async fn fetch_static(
     }
 }
 
-pub(crate) async fn fetch_static_str(config: &State<Config>, path: &str) -> anyhow::Result<String> {
+pub(crate) async fn fetch_static_str(
+    config: &State<Arc<Config>>,
+    path: &str,
+) -> anyhow::Result<String> {
     // we used to have code to cache this in a `OnceCell`, but that's unnecessary:
     // - when using a zip file, the data is already stored in-memory and is effectively never
     //   blocking on IO. zlib decompression is extremely fast.
@@ -150,7 +153,7 @@ pub(crate) async fn fetch_static_str(config: &State<Config>, path: &str) -> anyh
 
 #[get("/static/media/<path..>", rank = 0)]
 pub(crate) async fn static_media(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     path: PathBuf,
     range: Option<Range<'_>>,
 ) -> Result<Option<Static>> {
@@ -159,7 +162,7 @@ pub(crate) async fn static_media(
 
 #[get("/_before/<path..>", rank = 10)]
 pub(crate) async fn static_root(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     path: PathBuf,
     range: Option<Range<'_>>,
 ) -> Result<Option<Static>> {
@@ -0,0 +1,330 @@
+//! Prometheus metrics, served at `/_before/metrics` in the `OpenMetrics` text format.
+//!
+//! Counters and histograms are kept in [`METRICS`] and updated where things happen; gauges that
+//! can be read off existing state (cache sizes, socket.io sessions) are read when scraped.
+
+use crate::Config;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::{get, Data, Request, Response, State};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration as StdDuration, Instant};
+
+/// Histogram bucket upper bounds, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+lazy_static::lazy_static! {
+    pub(crate) static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    http_requests: Family<Counter>,
+    http_request_duration: Family<Histogram>,
+    chronicler_requests: Family<Counter>,
+    chronicler_request_duration: Family<Histogram>,
+    pub(crate) stream_cache_hits: AtomicU64,
+    pub(crate) stream_cache_misses: AtomicU64,
+    pub(crate) sse_streams: AtomicI64,
+    site_cache_refreshes: Family<Counter>,
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug)]
+struct Family<T>(Mutex<BTreeMap<Labels, T>>);
+
+impl<T> Default for Family<T> {
+    fn default() -> Family<T> {
+        Family(Mutex::new(BTreeMap::new()))
+    }
+}
+
+impl<T: Default> Family<T> {
+    fn with(&self, labels: Labels, f: impl FnOnce(&mut T)) {
+        f(self.0.lock().unwrap().entry(labels).or_default());
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counter(u64);
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: StdDuration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+impl Metrics {
+    pub(crate) fn chronicler_request(
+        &self,
+        route: String,
+        ty: Option<&str>,
+        ok: bool,
+        duration: StdDuration,
+    ) {
+        let labels = vec![
+            ("route", route),
+            ("type", ty.unwrap_or_default().to_owned()),
+        ];
+        let mut with_outcome = labels.clone();
+        with_outcome.push(("outcome", if ok { "ok" } else { "error" }.to_owned()));
+        self.chronicler_requests.with(with_outcome, |c| c.0 += 1);
+        self.chronicler_request_duration
+            .with(labels, |h| h.observe(duration));
+    }
+
+    pub(crate) fn site_cache_refresh(&self, ok: bool) {
+        let outcome = if ok { "ok" } else { "error" };
+        self.site_cache_refreshes
+            .with(vec![("outcome", outcome.to_owned())], |c| c.0 += 1);
+    }
+}
+
+/// Counts an active server-sent event stream until dropped.
+pub(crate) struct SseGuard(());
+
+impl SseGuard {
+    pub(crate) fn new() -> SseGuard {
+        METRICS.sse_streams.fetch_add(1, Ordering::Relaxed);
+        SseGuard(())
+    }
+}
+
+impl Drop for SseGuard {
+    fn drop(&mut self) {
+        METRICS.sse_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// Records request counts and latencies for each route.
+pub(crate) struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        request.local_cache(Instant::now);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let duration = request.local_cache(Instant::now).elapsed();
+        let route = request
+            .route()
+            .map_or_else(|| "unmatched".to_owned(), |route| route.uri.to_string());
+        let labels = vec![("method", request.method().to_string()), ("route", route)];
+        let mut with_status = labels.clone();
+        with_status.push(("status", response.status().code.to_string()));
+        METRICS.http_requests.with(with_status, |c| c.0 += 1);
+        METRICS
+            .http_request_duration
+            .with(labels, |h| h.observe(duration));
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[get("/_before/metrics")]
+pub(crate) async fn metrics(config: &State<Arc<Config>>) -> (ContentType, String) {
+    let (stream_cache_entries, stream_cache_bytes) = match &config.stream_cache {
+        Some(cache) => {
+            let cache = cache.lock().await;
+            (cache.len(), cache.bytes())
+        }
+        None => (0, 0),
+    };
+    let sessions = crate::socket_io::session_count().await;
+
+    let mut out = String::new();
+    counters(
+        &mut out,
+        "before_http_requests",
+        "HTTP requests handled, by route",
+        &METRICS.http_requests,
+    );
+    histograms(
+        &mut out,
+        "before_http_request_duration_seconds",
+        "Time until response headers are sent, by route",
+        &METRICS.http_request_duration,
+    );
+    counters(
+        &mut out,
+        "before_chronicler_requests",
+        "Chronicler requests, by route and entity type",
+        &METRICS.chronicler_requests,
+    );
+    histograms(
+        &mut out,
+        "before_chronicler_request_duration_seconds",
+        "Chronicler request latency, by route and entity type",
+        &METRICS.chronicler_request_duration,
+    );
+    counter(
+        &mut out,
+        "before_stream_cache_hits",
+        "Stream starts served from the stream cache",
+        METRICS.stream_cache_hits.load(Ordering::Relaxed),
+    );
+    counter(
+        &mut out,
+        "before_stream_cache_misses",
+        "Stream starts not served from the stream cache",
+        METRICS.stream_cache_misses.load(Ordering::Relaxed),
+    );
+    gauge(
+        &mut out,
+        "before_stream_cache_entries",
+        "Entries in the stream cache",
+        stream_cache_entries,
+    );
+    gauge(
+        &mut out,
+        "before_stream_cache_bytes",
+        "Approximate size of the data held by the stream cache",
+        stream_cache_bytes,
+    );
+    gauge(
+        &mut out,
+        "before_stream_timelines",
+        "Distinct timelines being streamed, each with one shared scheduler",
+        config.broadcaster.len(),
+    );
+    gauge(
+        &mut out,
+        "before_sse_streams",
+        "Active server-sent event streams",
+        METRICS.sse_streams.load(Ordering::Relaxed),
+    );
+    gauge(
+        &mut out,
+        "before_socket_io_sessions",
+        "Live socket.io sessions",
+        sessions,
+    );
+    counters(
+        &mut out,
+        "before_site_cache_refreshes",
+        "Refreshes of the site updates cache",
+        &METRICS.site_cache_refreshes,
+    );
+    out.push_str("# EOF\n");
+
+    (
+        ContentType::new("application", "openmetrics-text")
+            .with_params([("version", "1.0.0"), ("charset", "utf-8")]),
+        out,
+    )
+}
+
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {name} {ty}\n# HELP {name} {help}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{name}_total {value}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn counters(out: &mut String, name: &str, help: &str, family: &Family<Counter>) {
+    header(out, name, "counter", help);
+    for (labels, counter) in family.0.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "{name}_total{} {}",
+            format_labels(labels, None),
+            counter.0
+        );
+    }
+}
+
+fn histograms(out: &mut String, name: &str, help: &str, family: &Family<Histogram>) {
+    header(out, name, "histogram", help);
+    for (labels, histogram) in family.0.lock().unwrap().iter() {
+        for (le, bucket) in BUCKETS.iter().zip(histogram.buckets) {
+            let le = format!("{le:?}");
+            let labels = format_labels(labels, Some(&le));
+            let _ = writeln!(out, "{name}_bucket{labels} {bucket}");
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{} {}",
+            format_labels(labels, Some("+Inf")),
+            histogram.count
+        );
+        let labels = format_labels(labels, None);
+        let _ = writeln!(out, "{name}_sum{labels} {}", histogram.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", histogram.count);
+    }
+}
+
+fn format_labels(labels: &[(&str, String)], le: Option<&str>) -> String {
+    let mut s = String::from("{");
+    for (i, (k, v)) in labels
+        .iter()
+        .map(|(k, v)| (*k, v.as_str()))
+        .chain(le.map(|le| ("le", le)))
+        .enumerate()
+    {
+        if i > 0 {
+            s.push(',');
+        }
+        let v = v
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(s, "{k}=\"{v}\"");
+    }
+    s.push('}');
+    s
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[test]
+fn histogram_format() {
+    let family = Family::<Histogram>::default();
+    family.with(vec![("route", "/a\"b".into())], |h| {
+        h.observe(StdDuration::from_millis(30));
+    });
+    let mut out = String::new();
+    histograms(&mut out, "x_seconds", "help", &family);
+    assert!(out.contains("x_seconds_bucket{route=\"/a\\\"b\",le=\"0.025\"} 0\n"));
+    assert!(out.contains("x_seconds_bucket{route=\"/a\\\"b\",le=\"0.05\"} 1\n"));
+    assert!(out.contains("x_seconds_bucket{route=\"/a\\\"b\",le=\"+Inf\"} 1\n"));
+    assert!(out.contains("x_seconds_count{route=\"/a\\\"b\"} 1\n"));
+}
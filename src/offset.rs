@@ -11,18 +11,35 @@
 //! either an `X-Before-Time` header or a `_before_offset_time` query parameter containing the
 //! offset. This takes precedence over the `offset_sec` cookie, and is used by the modified client
 //! to keep the game state consistent even if you jump time in another browser window/tab.
+//!
+//! Time can also pass faster or slower than real time. The `playback_rate` cookie (or the
+//! `X-Before-Rate` header or `_before_rate` query parameter) contains a [`Playback`] rate and the
+//! wall-clock time it took effect at, its "anchor"; at the anchor, perceived time is the anchor
+//! minus the offset, and from then on it moves `rate` times as fast as wall-clock time. [`Clock`]
+//! combines the two. Without a rate, time passes at 1x and the offset alone determines perceived
+//! time.
+//!
+//! Time can also be paused, which freezes the perceived time at the anchor until it is resumed at
+//! the same rate. With auto-pause enabled, the `auto_pause` cookie records the last perceived time
+//! the client saw; if the client comes back after being away for a while, time resumes from there
+//! instead of from however far it would have moved on in the meantime. Page and stream routes take
+//! an [`AutoPaused`] clock rather than a [`Clock`] to do this.
 
 use crate::cookies::{AsCookie, CookieJarExt};
 use crate::time::DateTime;
 use anyhow::{anyhow, Error};
 use rocket::async_trait;
-use rocket::http::{CookieJar, Status};
+use rocket::http::{Cookie, CookieJar, Status};
 use rocket::request::{FromRequest, Outcome, Request};
 use std::fmt::{self, Display};
 use std::num::ParseIntError;
 use std::str::FromStr;
+use std::time::Duration as StdDuration;
 use time::{Duration, OffsetDateTime};
 
+/// The fastest playback rate a client can ask for.
+pub(crate) const MAX_RATE: f64 = 100.0;
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Offset(pub(crate) Duration);
 
@@ -73,14 +90,284 @@ impl<'r> FromRequest<'r> for Offset {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Playback {
+    pub(crate) rate: f64,
+    pub(crate) anchor: DateTime,
+    pub(crate) paused: bool,
+}
+
+impl Playback {
+    /// Returns the rate perceived time is currently passing at, which is zero while paused.
+    pub(crate) fn current(&self) -> f64 {
+        if self.paused {
+            0.0
+        } else {
+            self.rate
+        }
+    }
+
+    pub(crate) fn is_realtime(&self) -> bool {
+        !self.paused && (self.rate - 1.0).abs() < f64::EPSILON
+    }
+}
+
+impl Default for Playback {
+    fn default() -> Playback {
+        Playback {
+            rate: 1.0,
+            anchor: DateTime::new(OffsetDateTime::UNIX_EPOCH),
+            paused: false,
+        }
+    }
+}
+
+impl Display for Playback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.rate, self.anchor.0.unix_timestamp())?;
+        if self.paused {
+            write!(f, "@paused")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Playback {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Playback, Error> {
+        let (s, paused) = match s.strip_suffix("@paused") {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
+        let (rate, anchor) = s
+            .split_once('@')
+            .ok_or_else(|| anyhow!("rate is missing its anchor"))?;
+        let rate = f64::from_str(rate)?;
+        if !(rate > 0.0 && rate <= MAX_RATE) {
+            return Err(anyhow!("rate out of range"));
+        }
+        Ok(Playback {
+            rate,
+            anchor: DateTime::new(OffsetDateTime::from_unix_timestamp(anchor.parse()?)?),
+            paused,
+        })
+    }
+}
+
+impl AsCookie for Playback {
+    const NAME: &'static str = "playback_rate";
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Playback {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Playback, Error> {
+        Outcome::Success(
+            req.headers()
+                .get_one("X-Before-Rate")
+                .and_then(|c| c.parse().ok())
+                .or_else(|| {
+                    req.uri().query().and_then(|q| {
+                        q.segments().find_map(|(k, v)| {
+                            if k == "_before_rate" {
+                                v.parse().ok()
+                            } else {
+                                None
+                            }
+                        })
+                    })
+                })
+                .or_else(|| req.cookies().load::<Playback>())
+                .unwrap_or_default(),
+        )
+    }
+}
+
+/// The perceived time as a function of wall-clock time.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Clock {
+    pub(crate) offset: Offset,
+    pub(crate) playback: Playback,
+}
+
+impl Clock {
+    pub(crate) fn from_cookies(cookies: &CookieJar<'_>) -> Option<Clock> {
+        Some(Clock {
+            offset: cookies.load::<Offset>()?,
+            playback: cookies.load::<Playback>().unwrap_or_default(),
+        })
+    }
+
+    /// Returns the perceived time at wall-clock time `wall`.
+    pub(crate) fn time_at(&self, wall: DateTime) -> DateTime {
+        let anchor = self.playback.anchor;
+        anchor - self.offset.0 + (wall - anchor) * self.playback.current()
+    }
+
+    pub(crate) fn now(&self) -> DateTime {
+        self.time_at(DateTime::now())
+    }
+
+    /// Returns how much wall-clock time it takes for `duration` of perceived time to pass, or
+    /// `None` if time is paused.
+    pub(crate) fn wall_duration(&self, duration: Duration) -> Option<StdDuration> {
+        if self.playback.paused {
+            None
+        } else {
+            Some(StdDuration::try_from(duration / self.playback.rate).unwrap_or_default())
+        }
+    }
+
+    /// Returns a clock that perceives `time` at wall-clock time `wall`, keeping the playback rate.
+    pub(crate) fn jump(&self, time: DateTime, wall: DateTime) -> Result<Clock, Error> {
+        Clock::anchored(time, wall, self.playback.rate, self.playback.paused)
+    }
+
+    /// Returns a clock that continues from the current perceived time at a different rate.
+    pub(crate) fn with_rate(&self, rate: f64) -> Result<Clock, Error> {
+        let now = DateTime::now();
+        Clock::anchored(self.time_at(now), now, rate, self.playback.paused)
+    }
+
+    /// Returns a clock that is paused (or resumed) at the current perceived time.
+    pub(crate) fn with_paused(&self, paused: bool) -> Result<Clock, Error> {
+        let now = DateTime::now();
+        Clock::anchored(self.time_at(now), now, self.playback.rate, paused)
+    }
+
+    fn anchored(time: DateTime, wall: DateTime, rate: f64, paused: bool) -> Result<Clock, Error> {
+        let playback = Playback {
+            rate,
+            // both the offset and the anchor are stored as whole seconds
+            anchor: wall.trunc(Duration::SECOND)?,
+            paused,
+        };
+        let time = time - (wall - playback.anchor) * playback.current();
+        Ok(Clock {
+            offset: Offset(Duration::seconds((playback.anchor - time).whole_seconds())),
+            playback,
+        })
+    }
+
+    /// Stores the clock in the `offset_sec` and `playback_rate` cookies.
+    pub(crate) fn store(&self, cookies: &CookieJar<'_>) {
+        cookies.store(&self.offset);
+        if self.playback.is_realtime() {
+            cookies.remove(Cookie::named(Playback::NAME));
+        } else {
+            cookies.store(&self.playback);
+        }
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Clock {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Clock, Error> {
+        let playback = Playback::from_request(req)
+            .await
+            .succeeded()
+            .unwrap_or_default();
+        Offset::from_request(req)
+            .await
+            .map(|offset| Clock { offset, playback })
+    }
+}
+
+/// How long a client has to be away before auto-pause resumes from the last perceived time.
+const AUTO_PAUSE_AFTER: Duration = Duration::minutes(2);
+/// How often the last perceived time is recorded while a client is around.
+const AUTO_PAUSE_RECORD_INTERVAL: Duration = Duration::seconds(15);
+
+/// The last perceived time a client saw, and when. Auto-pause is enabled if this cookie is present.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AutoPause {
+    pub(crate) time: DateTime,
+    pub(crate) wall: DateTime,
+}
+
+impl Display for AutoPause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{}",
+            self.time.0.unix_timestamp(),
+            self.wall.0.unix_timestamp()
+        )
+    }
+}
+
+impl FromStr for AutoPause {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<AutoPause, Error> {
+        let (time, wall) = s
+            .split_once('@')
+            .ok_or_else(|| anyhow!("missing wall-clock time"))?;
+        Ok(AutoPause {
+            time: DateTime::new(OffsetDateTime::from_unix_timestamp(time.parse()?)?),
+            wall: DateTime::new(OffsetDateTime::from_unix_timestamp(wall.parse()?)?),
+        })
+    }
+}
+
+impl AsCookie for AutoPause {
+    const NAME: &'static str = "auto_pause";
+}
+
+impl AutoPause {
+    /// Records the client's perceived time, first resuming from the last recorded perceived time
+    /// if the client has been away. Returns the clock the client should now see.
+    fn apply(cookies: &CookieJar<'_>, mut clock: Clock, now: DateTime) -> Result<Clock, Error> {
+        let last = match cookies.load::<AutoPause>() {
+            Some(last) => last,
+            None => return Ok(clock),
+        };
+        let away = now - last.wall;
+        if away > AUTO_PAUSE_AFTER && !clock.playback.paused {
+            clock = clock.jump(last.time, now)?;
+            clock.store(cookies);
+        } else if away < AUTO_PAUSE_RECORD_INTERVAL {
+            return Ok(clock);
+        }
+        cookies.store(&AutoPause {
+            time: clock.time_at(now),
+            wall: now,
+        });
+        Ok(clock)
+    }
+}
+
+/// A [`Clock`] request guard for page and stream routes, which applies auto-pause to the clock the
+/// request asked for (including one from the `X-Before-Time` header) before handing it over.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AutoPaused(pub(crate) Clock);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for AutoPaused {
+    type Error = Error;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<AutoPaused, Error> {
+        Clock::from_request(req).await.map(|clock| {
+            AutoPaused(
+                AutoPause::apply(req.cookies(), clock, DateTime::now()).unwrap_or_else(|err| {
+                    log::warn!("failed to auto-pause: {err:#}");
+                    clock
+                }),
+            )
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct OffsetTime(pub(crate) DateTime);
 
 impl OffsetTime {
     pub(crate) fn from_cookies(cookies: &CookieJar<'_>) -> Option<OffsetTime> {
-        cookies
-            .load::<Offset>()
-            .map(|offset| OffsetTime(DateTime::new(OffsetDateTime::now_utc() - offset.0)))
+        Clock::from_cookies(cookies).map(|clock| OffsetTime(clock.now()))
     }
 }
 
@@ -89,8 +376,102 @@ impl<'r> FromRequest<'r> for OffsetTime {
     type Error = Error;
 
     async fn from_request(req: &'r Request<'_>) -> Outcome<OffsetTime, Error> {
-        Offset::from_request(req)
+        Clock::from_request(req)
             .await
-            .map(|offset| OffsetTime(DateTime::new(OffsetDateTime::now_utc() - offset.0)))
+            .map(|clock| OffsetTime(clock.now()))
     }
 }
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[test]
+fn accelerated_clock() {
+    use crate::time::datetime;
+
+    let wall = datetime!(2022-04-01 12:00:00.5 UTC);
+    let time = datetime!(2020-10-18 00:00:00 UTC);
+    let clock = Clock::anchored(time, wall, 4.0, false).unwrap();
+    // round-trip through the cookie format
+    let clock = Clock {
+        offset: clock.offset.to_string().parse().unwrap(),
+        playback: clock.playback.to_string().parse().unwrap(),
+    };
+
+    assert_eq!(clock.time_at(wall), time);
+    assert_eq!(
+        clock.time_at(wall + Duration::seconds(10)),
+        time + Duration::seconds(40)
+    );
+    assert_eq!(
+        clock.wall_duration(Duration::seconds(40)),
+        Some(StdDuration::from_secs(10))
+    );
+    assert_eq!(
+        clock.wall_duration(-Duration::SECOND),
+        Some(StdDuration::ZERO)
+    );
+
+    let paused = Clock::anchored(time, wall, 4.0, true).unwrap();
+    assert_eq!(paused.time_at(wall + Duration::hours(1)), time);
+    assert_eq!(paused.wall_duration(Duration::seconds(40)), None);
+}
+
+#[cfg(test)]
+#[rocket::get("/")]
+fn auto_pause_probe(clock: AutoPaused) -> String {
+    clock.0.now().to_string()
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn auto_pause_resumes() {
+    use crate::time::datetime;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+
+    let client = Client::tracked(rocket::build().mount("/", rocket::routes![auto_pause_probe]))
+        .await
+        .unwrap();
+    let last = AutoPause {
+        time: datetime!(2020-10-18 00:00:00 UTC),
+        wall: DateTime::now() - Duration::minutes(10),
+    };
+    let near = |a: DateTime, b: DateTime| (a - b).abs() <= Duration::seconds(2);
+    let body = |response: String| response.parse::<DateTime>().unwrap();
+
+    // away for longer than `AUTO_PAUSE_AFTER`: resume from the last perceived time, even though
+    // the modified client sent its (stale) offset in a header
+    let response = client
+        .get("/")
+        .header(Header::new("X-Before-Time", "3600"))
+        .cookie(Cookie::new(AutoPause::NAME, last.to_string()))
+        .dispatch()
+        .await;
+    let offset: Offset = (response.cookies().get(Offset::NAME).unwrap().value())
+        .parse()
+        .unwrap();
+    let recorded: AutoPause = (response.cookies().get(AutoPause::NAME).unwrap().value())
+        .parse()
+        .unwrap();
+    assert!(near(DateTime::now() - offset.0, last.time));
+    assert!(near(recorded.time, last.time));
+    assert!(near(recorded.wall, DateTime::now()));
+    assert!(near(body(response.into_string().await.unwrap()), last.time));
+
+    // paused clocks stay where they are
+    let paused =
+        Clock::anchored(last.time - Duration::days(1), DateTime::now(), 1.0, true).unwrap();
+    let response = client
+        .get("/")
+        .cookie(Cookie::new(Offset::NAME, paused.offset.to_string()))
+        .cookie(Cookie::new(Playback::NAME, paused.playback.to_string()))
+        .cookie(Cookie::new(AutoPause::NAME, last.to_string()))
+        .dispatch()
+        .await;
+    assert!(response.cookies().get(Offset::NAME).is_none());
+    assert!(near(
+        body(response.into_string().await.unwrap()),
+        paused.now()
+    ));
+}
@@ -8,6 +8,7 @@ use rocket::{get, request::FromParam, State};
 use std::collections::BTreeSet;
 use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use time::OffsetDateTime;
 
 #[derive(Debug, Clone, Copy)]
@@ -42,7 +43,7 @@ impl<'a> FromParam<'a> for Site {
 
 #[get("/_before/<domain>/<path..>", rank = 9)]
 pub(crate) async fn offsite(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     domain: Site,
     mut path: PathBuf,
     time: Option<OffsetTime>,
@@ -101,7 +102,7 @@ pub(crate) async fn offsite(
     }
 }
 
-fn read_dir_static(config: &State<Config>, domain: Site) -> impl Stream<Item = OsString> + '_ {
+fn read_dir_static(config: &State<Arc<Config>>, domain: Site) -> impl Stream<Item = OsString> + '_ {
     stream! {
         if let Some(zip) = config.static_zip.as_ref().cloned() {
             for file in zip.file_names() {
@@ -1,6 +1,8 @@
 use crate::chronicler::{fix_id, Order, RequestBuilder};
 use crate::offset::OffsetTime;
+use crate::provenance::{DebugMode, Provenance, Source, Transform};
 use crate::time::{datetime, DateTime};
+use crate::upstream::Priority;
 use crate::{Config, Result};
 use itertools::Itertools;
 use rocket::futures::{future::try_join_all, TryStreamExt};
@@ -10,6 +12,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 lazy_static::lazy_static! {
     static ref NUDGES: HashMap<String, BTreeMap<DateTime, Option<Nudge>>> =
@@ -29,13 +32,39 @@ enum Nudge {
     Replace(serde_json::Value),
 }
 
+impl Nudge {
+    /// Returns the nudged player, for a nudge in effect since `since`.
+    async fn fetch(
+        &self,
+        config: &Config,
+        id: &str,
+        since: DateTime,
+    ) -> anyhow::Result<Option<(Box<RawValue>, Provenance)>> {
+        Ok(match self {
+            Nudge::Forward(end) => config
+                .fetch_versions("Player", Some(id.to_owned()), *end)
+                .await?
+                .next()
+                .map(|version| {
+                    let provenance = Provenance::new(Source::PlayerNudge, Some(version.valid_from));
+                    (version.data, provenance)
+                }),
+            Nudge::Replace(v) => Some((
+                serde_json::from_value(v.clone())?,
+                Provenance::new(Source::PlayerNudge, Some(since)),
+            )),
+        })
+    }
+}
+
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
 #[get("/database/players?<ids>")]
 pub(crate) async fn players(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     ids: &str,
     time: OffsetTime,
+    debug: DebugMode,
 ) -> Result<Json<Vec<Box<RawValue>>>> {
     const HALL_REVEALED: DateTime = datetime!(2020-09-20 19:18:00 UTC);
     const HALL_FIXED: DateTime = datetime!(2020-09-23 12:00:00 UTC);
@@ -52,11 +81,11 @@ pub(crate) async fn players(
             match NUDGES
                 .get(*id)
                 .and_then(|nudges| nudges.range(..time.0).rev().next())
-                .and_then(|(_, nudge)| nudge.as_ref())
+                .and_then(|(since, nudge)| Some((since, nudge.as_ref()?)))
             {
-                Some(Nudge::Forward(end)) if time.0 >= *end => true,
-                Some(nudge) => {
-                    nudges.push((*id, nudge));
+                Some((_, Nudge::Forward(end))) if time.0 >= *end => true,
+                Some((since, nudge)) => {
+                    nudges.push((*id, *since, nudge));
                     false
                 }
                 None => true,
@@ -64,47 +93,39 @@ pub(crate) async fn players(
         })
         .join(",");
 
-    let mut players = HashMap::new();
-    for (id, nudge) in nudges {
-        match nudge {
-            Nudge::Forward(end) => {
-                if let Some(player) = config
-                    .fetch("Player", Some(id.to_owned()), *end)
-                    .await?
-                    .next()
-                {
-                    players.insert(Cow::Borrowed(id), player);
-                }
-            }
-            Nudge::Replace(v) => {
-                players.insert(
-                    Cow::Borrowed(id),
-                    serde_json::from_value(v.clone()).map_err(anyhow::Error::from)?,
-                );
-            }
-        };
-    }
+    // forwarded players with the same nudge end time are batched together by `Config::fetch`
+    let mut players: HashMap<_, _> =
+        try_join_all(nudges.into_iter().map(|(id, since, nudge)| async move {
+            let player = nudge.fetch(config, id, since).await?;
+            anyhow::Ok(player.map(|player| (Cow::Borrowed(id), player)))
+        }))
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
     if !remaining_ids.is_empty() {
+        // heuristically detect a query for hall of flame players with missing attributes
+        let at = if time.0 > HALL_REVEALED && ids.contains("d74a2473-1f29-40fa-a41e-66fa2281dfca") {
+            std::cmp::max(HALL_FIXED, time.0)
+        } else {
+            time.0
+        };
         players.extend(
             RequestBuilder::v2("entities")
                 .ty("Player")
-                .at(
-                    // heuristically detect a query for hall of flame players with missing
-                    // attributes
-                    if time.0 > HALL_REVEALED
-                        && ids.contains("d74a2473-1f29-40fa-a41e-66fa2281dfca")
-                    {
-                        std::cmp::max(HALL_FIXED, time.0)
-                    } else {
-                        time.0
-                    },
-                )
+                .at(at)
                 .id(remaining_ids)
                 .json(config)
                 .await?
                 .items
                 .into_iter()
-                .map(|version| (Cow::Owned(version.entity_id), version.data)),
+                .map(|version| {
+                    let mut provenance = Provenance::chronicler(&version);
+                    if at != time.0 {
+                        provenance = provenance.with(Transform::LaterVersion);
+                    }
+                    (Cow::Owned(version.entity_id), (version.data, provenance))
+                }),
         );
     }
 
@@ -127,18 +148,25 @@ pub(crate) async fn players(
         .await?
         .into_iter()
         .filter_map(|versions| {
-            versions
-                .items
-                .into_iter()
-                .next()
-                .map(|version| (Cow::Owned(version.entity_id), version.data))
+            versions.items.into_iter().next().map(|version| {
+                let provenance = Provenance::chronicler(&version).with(Transform::EarliestVersion);
+                (Cow::Owned(version.entity_id), (version.data, provenance))
+            })
         }),
     );
 
     // Combine a final list of players in the originally-provided ID order.
     Ok(Json(
         ids.split(',')
-            .filter_map(|id| players.remove(id).map(|p| fix_id(p, time.0)))
+            .filter_map(|id| players.remove(id))
+            .map(|(player, mut provenance)| {
+                let len = player.get().len();
+                let player = fix_id(player, time.0)?;
+                if player.get().len() != len {
+                    provenance = provenance.with(Transform::FixId);
+                }
+                debug.annotate(player, &provenance)
+            })
             .collect::<anyhow::Result<_>>()?,
     ))
 }
@@ -147,12 +175,13 @@ pub(crate) async fn players(
 
 #[get("/database/playerNamesIds")]
 pub(crate) async fn player_names_ids(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     time: OffsetTime,
 ) -> Result<Json<Vec<PlayerNameId>>> {
     let mut v: Vec<PlayerNameId> = RequestBuilder::v2("entities")
         .ty("Player")
         .at(time.0)
+        .priority(Priority::Background)
         .paged_json(config)
         .map_ok(|v| v.data)
         .try_collect()
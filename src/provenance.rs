@@ -0,0 +1,149 @@
+//! Debug annotations describing where response data came from.
+//!
+//! Requests with an `X-Before-Debug: 1` header get a `_before` key added to stream events and to
+//! each entity in supported `/database` and `/api` responses. It holds a [`Provenance`]: the
+//! source of the data, the `valid_from` of the version it was taken from (if any), and any
+//! transforms applied to it. Stream events hold one per component, keyed by component name.
+
+use crate::chronicler::Version;
+use crate::time::DateTime;
+use rocket::async_trait;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::convert::Infallible;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Source {
+    /// A version from Chronicler (or the configured data source).
+    Chronicler,
+    /// An event from `data/inject.json`.
+    Inject,
+    /// A player override from `data/playernudge.json`.
+    PlayerNudge,
+    /// Rebuilt from other entities, because the stream had no recent value for it.
+    Constructed,
+    /// Reconstructed during a gap in the stream (see `stream::gaps`).
+    Synthesized,
+    /// Made up, because there was nothing to go on (such as `first_temporal`'s `whatistime`).
+    Fallback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Transform {
+    /// `id` and `_id` keys were normalized to match the era (see `chronicler::fix_id`).
+    FixId,
+    /// A later version than the requested time was used, to work around data Chronicler picked
+    /// up late.
+    LaterVersion,
+    /// Nothing existed at the requested time, so the earliest known version was used.
+    EarliestVersion,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Provenance {
+    pub(crate) source: Source,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) valid_from: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) transforms: Vec<Transform>,
+}
+
+impl Provenance {
+    pub(crate) fn new(source: Source, valid_from: Option<DateTime>) -> Provenance {
+        Provenance {
+            source,
+            valid_from,
+            transforms: Vec::new(),
+        }
+    }
+
+    pub(crate) fn chronicler<T>(version: &Version<T>) -> Provenance {
+        Provenance::new(Source::Chronicler, Some(version.valid_from))
+    }
+
+    pub(crate) fn with(mut self, transform: Transform) -> Provenance {
+        self.transforms.push(transform);
+        self
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// Whether the request asked for provenance annotations with `X-Before-Debug`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DebugMode(pub(crate) bool);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for DebugMode {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<DebugMode, Infallible> {
+        let header = req.headers().get_one("X-Before-Debug");
+        Outcome::Success(DebugMode(header.map_or(false, |v| v == "1" || v == "true")))
+    }
+}
+
+impl DebugMode {
+    /// Returns `value`, annotated with `provenance` if enabled.
+    pub(crate) fn annotate(
+        self,
+        value: Box<RawValue>,
+        provenance: &impl Serialize,
+    ) -> anyhow::Result<Box<RawValue>> {
+        if !self.0 {
+            return Ok(value);
+        }
+        Ok(match sidecar(value.get(), provenance)? {
+            Some(annotated) => RawValue::from_string(annotated)?,
+            None => value,
+        })
+    }
+
+    /// Returns an entity version's data, annotated if enabled.
+    pub(crate) fn entity(self, version: Version<Box<RawValue>>) -> anyhow::Result<Box<RawValue>> {
+        let provenance = Provenance::chronicler(&version);
+        self.annotate(version.data, &provenance)
+    }
+}
+
+/// Adds a `_before` key containing `provenance` to a serialized JSON object. Returns `None` if
+/// `json` isn't an object.
+pub(crate) fn sidecar(
+    json: &str,
+    provenance: &impl Serialize,
+) -> serde_json::Result<Option<String>> {
+    let json = json.trim_end();
+    let body = match json.strip_suffix('}') {
+        Some(body) if json.starts_with('{') => body,
+        _ => return Ok(None),
+    };
+    let separator = if body.trim_end() == "{" { "" } else { "," };
+    Ok(Some(format!(
+        "{body}{separator}\"_before\":{}}}",
+        serde_json::to_string(provenance)?
+    )))
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[test]
+fn add_sidecar() {
+    use crate::time::datetime;
+
+    let provenance = Provenance::new(Source::Chronicler, Some(datetime!(2021-03-01 00:00:00 UTC)))
+        .with(Transform::FixId);
+    assert_eq!(
+        sidecar(r#"{"id":"a"}"#, &provenance).unwrap().unwrap(),
+        r#"{"id":"a","_before":{"source":"chronicler","validFrom":"2021-03-01T00:00:00Z","transforms":["fix_id"]}}"#
+    );
+    assert_eq!(
+        sidecar("{ }", &provenance).unwrap().unwrap(),
+        r#"{ "_before":{"source":"chronicler","validFrom":"2021-03-01T00:00:00Z","transforms":["fix_id"]}}"#
+    );
+    assert_eq!(sidecar("[]", &provenance).unwrap(), None);
+}
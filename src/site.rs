@@ -1,12 +1,15 @@
 use crate::chronicler::{Data, Order, RequestBuilder};
 use crate::http::{ETag, Proxy};
+use crate::metrics::METRICS;
 use crate::offset::OffsetTime;
+use crate::source::Download;
 use crate::time::{datetime, DateTime, Duration};
 use crate::Config;
 use rocket::http::uri::Origin;
 use rocket::{get, Responder, State};
 use serde::Deserialize;
 use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 use tokio::sync::RwLock;
 
 lazy_static::lazy_static! {
@@ -45,16 +48,8 @@ struct SiteUpdate {
 }
 
 impl SiteUpdate {
-    async fn fetch(&self, config: &Config) -> anyhow::Result<reqwest::Response> {
-        Ok(config
-            .client
-            .get(format!(
-                "{}v1{}",
-                config.chronicler_base_url, self.download_url
-            ))
-            .send()
-            .await?
-            .error_for_status()?)
+    async fn fetch(&self, config: &Config) -> anyhow::Result<Download> {
+        config.source.download(&self.download_url).await
     }
 }
 
@@ -127,7 +122,9 @@ pub(crate) async fn update_cache(config: &Config, at: DateTime) -> anyhow::Resul
     }
     log::debug!("updating v1/site/updates cache");
 
-    let response: Data<SiteUpdate> = request.json(config).await?;
+    let response: anyhow::Result<Data<SiteUpdate>> = request.json(config).await;
+    METRICS.site_cache_refresh(response.is_ok());
+    let response = response?;
     if !response.data.is_empty() {
         let mut cache = CACHE.write().await;
         for mut update in response.data {
@@ -168,7 +165,7 @@ pub(crate) struct Response {
 pub(crate) async fn site_static(
     origin: &Origin<'_>,
     time: OffsetTime,
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
 ) -> crate::Result<Option<Response>> {
     update_cache(config, time.0).await?;
     let cache = CACHE.read().await;
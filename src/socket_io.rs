@@ -2,27 +2,27 @@
 //! code to only use polling, and these functions implement the protocol.
 
 use crate::config::Config;
-use crate::offset::{Offset, OffsetTime};
+use crate::offset::AutoPaused;
 use crate::stream::{self, Item};
 use crate::Result;
 use rand::{thread_rng, Rng};
 use rocket::futures::{Stream, StreamExt};
 use rocket::{get, post, Shutdown, State};
-use serde::Serialize;
 use serde_json::json;
+use serde_json::value::RawValue;
 use std::borrow::Borrow;
 use std::collections::{HashMap, VecDeque};
 use std::hash::Hash;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::time::{Duration as StdDuration, Instant};
 use tokio::{select, sync::Mutex, time::sleep};
 
 #[get("/socket.io?<sid>")]
 pub(crate) async fn socket_io(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     sid: Option<u64>,
-    time: OffsetTime,
-    offset: Offset,
+    clock: AutoPaused,
     shutdown: Shutdown,
 ) -> Result<String> {
     if let Some(sid) = sid {
@@ -39,18 +39,18 @@ pub(crate) async fn socket_io(
                     _ = sleep(StdDuration::from_secs(15)) => None,
                 } {
                     Some(Some(v)) => {
-                        session.serialized.extend(v.into_eio()?);
+                        session.serialized.extend(v.into_eio());
                         match session.serialized.pop_front() {
                             Some(v) => v,
-                            None => eio_payload(&())?,
+                            None => eio_noop(),
                         }
                     }
                     Some(None) => {
                         session.stream =
-                            Box::pin(stream::start(config, time, offset, shutdown).await?);
-                        eio_payload(&())?
+                            Box::pin(stream::start(config, clock.0, None, shutdown).await?);
+                        eio_noop()
                     }
-                    None => eio_payload(&())?,
+                    None => eio_noop(),
                 }
             };
 
@@ -64,7 +64,7 @@ pub(crate) async fn socket_io(
         new_sid,
         Session {
             serialized: VecDeque::new(),
-            stream: Box::pin(stream::start(config, time, offset, shutdown).await?),
+            stream: Box::pin(stream::start(config, clock.0, None, shutdown).await?),
         },
     );
     let payload = format!(
@@ -96,28 +96,35 @@ pub(crate) async fn socket_io_post(sid: Option<u64>, data: &[u8]) -> &'static st
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
 impl Item {
-    fn into_eio(self) -> anyhow::Result<Vec<String>> {
+    fn into_eio(self) -> Vec<String> {
         match self {
-            Item::Start(first) => Ok(vec![
-                eio_payload(&("gameDataUpdate", &first.games))?,
-                eio_payload(&("leagueDataUpdate", &first.leagues))?,
-                eio_payload(&("temporalDataUpdate", &first.temporal))?,
-            ]),
+            Item::Start(_, first) => vec![
+                eio_payload("gameDataUpdate", &first.games),
+                eio_payload("leagueDataUpdate", &first.leagues),
+                eio_payload("temporalDataUpdate", &first.temporal),
+            ],
             Item::Update(value) => vec![
                 ("gameDataUpdate", &value.data.value.games),
                 ("leagueDataUpdate", &value.data.value.leagues),
                 ("temporalDataUpdate", &value.data.value.temporal),
             ]
             .into_iter()
-            .filter_map(|(k, v)| v.as_ref().map(|v| eio_payload(&(k, v))))
+            .filter_map(|(k, v)| v.as_ref().map(|v| eio_payload(k, v)))
             .collect(),
         }
     }
 }
 
-fn eio_payload<T: Serialize>(value: &T) -> anyhow::Result<String> {
-    let payload = format!("42{}", serde_json::to_string(&value)?);
-    Ok(format!("{}:{}", payload.encode_utf16().count(), payload))
+/// The packet sent when there's nothing new, which the client ignores before polling again.
+fn eio_noop() -> String {
+    // a `null` event, length-prefixed
+    "6:42null".to_owned()
+}
+
+/// Builds a socket.io event packet, writing the already-serialized component as-is.
+fn eio_payload(event: &str, value: &RawValue) -> String {
+    let payload = format!("42[\"{event}\",{}]", value.get());
+    format!("{}:{}", payload.encode_utf16().count(), payload)
 }
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
@@ -128,7 +135,11 @@ lazy_static::lazy_static! {
 
 struct Session {
     serialized: VecDeque<String>,
-    stream: Pin<Box<dyn Stream<Item = Item> + Send + Sync>>,
+    stream: Pin<Box<dyn Stream<Item = Item> + Send>>,
+}
+
+pub(crate) async fn session_count() -> usize {
+    SESSIONS.lock().await.inner.len()
 }
 
 pub(crate) async fn remove_expired_sessions() {
@@ -0,0 +1,665 @@
+//! A local, read-only archive of Chronicler data, for running Before without a network.
+//!
+//! An archive is a directory containing:
+//!
+//! - `entities/{type}.ndjson`: one `v2/versions` item (`entityId`, `validFrom`, `data`) per line,
+//!   for every entity type, including `Stream`
+//! - `games.ndjson`: one `v1/games/updates` item (`gameId`, `timestamp`, `data`) per line
+//! - `site.ndjson`: one `v1/site/updates` item per line
+//!
+//! At startup, each file is read through once to index where every record is, along with the
+//! metadata `v1/games` filters on. Records themselves stay on disk and are read back as queries
+//! need them. Archives are built from Chronicler JSON responses with `before ingest`; see
+//! [`ingest`].
+
+use crate::chronicler::{Data, Order, Query, Version, Versions};
+use crate::source::DataSource;
+use crate::time::DateTime;
+use anyhow::{bail, ensure, Context, Result};
+use rocket::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, BufReader};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GameUpdate {
+    game_id: String,
+    timestamp: DateTime,
+    data: Box<RawValue>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GameUpdateRef<'a> {
+    game_id: &'a str,
+    timestamp: DateTime,
+    data: &'a RawValue,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScheduledGame<'a> {
+    game_id: &'a str,
+    start_time: DateTime,
+    data: &'a RawValue,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GameMeta {
+    season: i64,
+    day: i64,
+    #[serde(default)]
+    sim: Option<String>,
+    #[serde(default)]
+    game_start: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SiteUpdate {
+    timestamp: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Item<'a> {
+    entity_id: &'a str,
+    valid_from: DateTime,
+    valid_to: Option<DateTime>,
+    data: Box<RawValue>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Page<T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_page: Option<String>,
+    #[serde(flatten)]
+    items: T,
+}
+
+#[derive(Debug, Serialize)]
+struct Items<T> {
+    items: Vec<T>,
+}
+
+#[derive(Debug, Serialize)]
+struct DataItems<T> {
+    data: Vec<T>,
+}
+
+/// Where a record's line is in one of the archive's files.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    file: usize,
+    offset: u64,
+    len: usize,
+}
+
+type History = BTreeMap<DateTime, Span>;
+
+#[derive(Debug, Default)]
+struct Game {
+    updates: History,
+    /// The metadata in the latest update, if it has any.
+    meta: Option<GameMeta>,
+    /// The first update where the game had started.
+    started: Option<DateTime>,
+}
+
+impl Game {
+    fn add(&mut self, timestamp: DateTime, data: &RawValue, span: Span) {
+        let meta = serde_json::from_str::<GameMeta>(data.get()).ok();
+        if meta.as_ref().map_or(false, |meta| meta.game_start) {
+            self.started = Some(self.started.map_or(timestamp, |t| t.min(timestamp)));
+        }
+        if self
+            .updates
+            .keys()
+            .next_back()
+            .map_or(true, |t| timestamp >= *t)
+        {
+            self.meta = meta;
+        }
+        self.updates.insert(timestamp, span);
+    }
+
+    fn start_time(&self) -> Option<DateTime> {
+        self.started.or_else(|| self.updates.keys().next().copied())
+    }
+}
+
+/// An entity version, before its data is read.
+struct Record<'a> {
+    entity_id: &'a str,
+    valid_from: DateTime,
+    valid_to: Option<DateTime>,
+    span: Span,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Archive {
+    files: Vec<PathBuf>,
+    /// type -> entity id -> valid from -> line
+    entities: HashMap<String, BTreeMap<String, History>>,
+    /// game id -> updates and metadata
+    games: BTreeMap<String, Game>,
+    site: BTreeMap<DateTime, Vec<Span>>,
+}
+
+impl Archive {
+    pub(crate) async fn open(path: &Path) -> Result<Archive> {
+        let mut archive = Archive::default();
+
+        let mut dir = tokio::fs::read_dir(path.join("entities"))
+            .await
+            .with_context(|| format!("failed to open archive at {}", path.display()))?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            let ty = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(ty) if path.extension().map_or(false, |ext| ext == "ndjson") => ty.to_owned(),
+                _ => continue,
+            };
+            let file = archive.files.len();
+            let entities = archive.entities.entry(ty).or_default();
+            scan(&path, file, |line, span| {
+                let version: Version<&RawValue> = serde_json::from_str(line)?;
+                entities
+                    .entry(version.entity_id)
+                    .or_default()
+                    .insert(version.valid_from, span);
+                Ok(())
+            })
+            .await?;
+            archive.files.push(path);
+        }
+
+        let games = &mut archive.games;
+        let file = archive.files.len();
+        if scan(&path.join("games.ndjson"), file, |line, span| {
+            #[derive(Deserialize)]
+            #[serde(rename_all = "camelCase")]
+            struct Update<'a> {
+                game_id: String,
+                timestamp: DateTime,
+                #[serde(borrow)]
+                data: &'a RawValue,
+            }
+
+            let update: Update<'_> = serde_json::from_str(line)?;
+            let game = games.entry(update.game_id).or_default();
+            game.add(update.timestamp, update.data, span);
+            Ok(())
+        })
+        .await?
+        {
+            archive.files.push(path.join("games.ndjson"));
+        }
+
+        let site = &mut archive.site;
+        let file = archive.files.len();
+        let mut seen = HashSet::new();
+        if scan(&path.join("site.ndjson"), file, |line, span| {
+            let timestamp = serde_json::from_str::<SiteUpdate>(line)?.timestamp;
+            let mut hasher = DefaultHasher::new();
+            line.hash(&mut hasher);
+            if seen.insert(hasher.finish()) {
+                site.entry(timestamp).or_default().push(span);
+            }
+            Ok(())
+        })
+        .await?
+        {
+            archive.files.push(path.join("site.ndjson"));
+        }
+
+        log::info!(
+            "loaded archive from {} ({} entity types, {} games)",
+            path.display(),
+            archive.entities.len(),
+            archive.games.len()
+        );
+        Ok(archive)
+    }
+
+    /// Reads and parses the lines at `spans`, in order.
+    async fn read<T: DeserializeOwned>(&self, spans: Vec<Span>) -> Result<Vec<T>> {
+        let mut files = HashMap::new();
+        let mut records = Vec::new();
+        for span in spans {
+            let file = match files.entry(span.file) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(File::open(&self.files[span.file]).await?),
+            };
+            file.seek(SeekFrom::Start(span.offset)).await?;
+            let mut buf = vec![0; span.len];
+            file.read_exact(&mut buf).await?;
+            records.push(serde_json::from_slice(&buf).with_context(|| {
+                format!(
+                    "{} changed since the archive was opened",
+                    self.files[span.file].display()
+                )
+            })?);
+        }
+        Ok(records)
+    }
+
+    async fn entities(&self, query: &Query) -> Result<String> {
+        let at = query.at.unwrap_or_else(DateTime::now);
+        let records = self
+            .histories(query)?
+            .filter_map(|(id, history)| {
+                let (valid_from, span) = history.range(..=at).next_back()?;
+                Some(Record {
+                    entity_id: id,
+                    valid_from: *valid_from,
+                    valid_to: next_key(history, *valid_from),
+                    span: *span,
+                })
+            })
+            .collect();
+        self.versions_response(query, records).await
+    }
+
+    async fn versions(&self, query: &Query) -> Result<String> {
+        let mut records = self
+            .histories(query)?
+            .flat_map(|(id, history)| {
+                history
+                    .range(range(query.after, query.before))
+                    .map(move |(valid_from, span)| Record {
+                        entity_id: id,
+                        valid_from: *valid_from,
+                        valid_to: next_key(history, *valid_from),
+                        span: *span,
+                    })
+            })
+            .collect::<Vec<_>>();
+        records.sort_by_key(|record| (record.valid_from, record.entity_id));
+        if let Some(Order::Desc) = query.order {
+            records.reverse();
+        }
+        self.versions_response(query, records).await
+    }
+
+    async fn versions_response(&self, query: &Query, records: Vec<Record<'_>>) -> Result<String> {
+        let (records, next_page) = paginate(query, records)?;
+        let versions: Vec<Version<Box<RawValue>>> = self
+            .read(records.iter().map(|record| record.span).collect())
+            .await?;
+        let items = records
+            .into_iter()
+            .zip(versions)
+            .map(|(record, version)| Item {
+                entity_id: record.entity_id,
+                valid_from: record.valid_from,
+                valid_to: record.valid_to,
+                data: version.data,
+            })
+            .collect();
+        Ok(serde_json::to_string(&Page {
+            next_page,
+            items: Items { items },
+        })?)
+    }
+
+    fn histories<'a>(
+        &'a self,
+        query: &'a Query,
+    ) -> Result<Box<dyn Iterator<Item = (&'a str, &'a History)> + Send + 'a>> {
+        let ty = query.ty.context("type is required")?;
+        let entities = match self.entities.get(ty) {
+            Some(entities) => entities,
+            None => return Ok(Box::new(std::iter::empty())),
+        };
+        Ok(match &query.id {
+            Some(ids) => Box::new(
+                ids.split(',')
+                    .filter_map(move |id| entities.get_key_value(id))
+                    .map(|(id, history)| (id.as_str(), history)),
+            ),
+            None => Box::new(entities.iter().map(|(id, history)| (id.as_str(), history))),
+        })
+    }
+
+    async fn games(&self, query: &Query) -> Result<String> {
+        let mut games = self
+            .games
+            .iter()
+            .filter_map(|(id, game)| {
+                let meta = game.meta.as_ref()?;
+                let matches = query.season.map_or(true, |season| season == meta.season)
+                    && query.day.map_or(true, |day| day == meta.day)
+                    && query
+                        .started
+                        .map_or(true, |started| started == meta.game_start)
+                    && query.sim.map_or(true, |sim| {
+                        meta.sim.as_deref().unwrap_or("thisidisstaticyo") == sim
+                    });
+                if !matches {
+                    return None;
+                }
+                let (_, span) = game.updates.iter().next_back()?;
+                Some((game.start_time()?, id.as_str(), *span))
+            })
+            .collect::<Vec<_>>();
+        games.sort_by_key(|(start_time, id, _)| (*start_time, *id));
+        let updates: Vec<GameUpdate> = self
+            .read(games.iter().map(|(_, _, span)| *span).collect())
+            .await?;
+        let data = games
+            .iter()
+            .zip(&updates)
+            .map(|((start_time, id, _), update)| ScheduledGame {
+                game_id: id,
+                start_time: *start_time,
+                data: &update.data,
+            })
+            .collect();
+        Ok(serde_json::to_string(&DataItems { data })?)
+    }
+
+    async fn game_updates(&self, query: &Query) -> Result<String> {
+        let ids = query.game.as_deref().context("game is required")?;
+        let mut updates = ids
+            .split(',')
+            .filter_map(|id| self.games.get_key_value(id))
+            .flat_map(|(id, game)| {
+                game.updates
+                    .range(range(query.after, query.before))
+                    .map(move |(timestamp, span)| (*timestamp, id.as_str(), *span))
+            })
+            .collect::<Vec<_>>();
+        updates.sort_by_key(|(timestamp, id, _)| (*timestamp, *id));
+        if let Some(Order::Desc) = query.order {
+            updates.reverse();
+        }
+        let (updates, next_page) = paginate(query, updates)?;
+        let data: Vec<GameUpdate> = self
+            .read(updates.iter().map(|(_, _, span)| *span).collect())
+            .await?;
+        let data = updates
+            .iter()
+            .zip(&data)
+            .map(|((timestamp, id, _), update)| GameUpdateRef {
+                game_id: id,
+                timestamp: *timestamp,
+                data: &update.data,
+            })
+            .collect();
+        Ok(serde_json::to_string(&Page {
+            next_page,
+            items: DataItems { data },
+        })?)
+    }
+
+    async fn site_updates(&self, query: &Query) -> Result<String> {
+        let mut spans = self
+            .site
+            .range(range(query.after, query.before))
+            .flat_map(|(_, spans)| spans.iter().copied())
+            .collect::<Vec<_>>();
+        if let Some(Order::Desc) = query.order {
+            spans.reverse();
+        }
+        let data: Vec<Box<RawValue>> = self.read(spans).await?;
+        Ok(serde_json::to_string(&DataItems { data })?)
+    }
+}
+
+#[async_trait]
+impl DataSource for Archive {
+    async fn get(&self, query: &Query) -> Result<String> {
+        match (query.version, query.route) {
+            ("v2", "entities") => self.entities(query).await,
+            ("v2", "versions") => self.versions(query).await,
+            ("v1", "games") => self.games(query).await,
+            ("v1", "games/updates") => self.game_updates(query).await,
+            ("v1", "site/updates") => self.site_updates(query).await,
+            (version, route) => bail!("{version}/{route} is not available in the archive"),
+        }
+    }
+}
+
+/// Calls `f` with each line of an NDJSON file and where it is, reading the file a line at a time.
+/// Returns `false` if the file doesn't exist.
+async fn scan(
+    path: &Path,
+    file: usize,
+    mut f: impl FnMut(&str, Span) -> Result<()>,
+) -> Result<bool> {
+    let mut reader = match File::open(path).await {
+        Ok(reader) => BufReader::new(reader),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    let mut line = String::new();
+    let mut offset = 0;
+    for n in 1.. {
+        line.clear();
+        let read = reader.read_line(&mut line).await?;
+        if read == 0 {
+            break;
+        }
+        let trimmed = line.trim_end_matches(['\n', '\r']);
+        if !trimmed.is_empty() {
+            let span = Span {
+                file,
+                offset,
+                len: trimmed.len(),
+            };
+            f(trimmed, span).with_context(|| format!("{}:{n}", path.display()))?;
+        }
+        offset += read as u64;
+    }
+    Ok(true)
+}
+
+fn next_key(history: &History, key: DateTime) -> Option<DateTime> {
+    history
+        .range((std::ops::Bound::Excluded(key), std::ops::Bound::Unbounded))
+        .next()
+        .map(|(time, _)| *time)
+}
+
+fn range(
+    after: Option<DateTime>,
+    before: Option<DateTime>,
+) -> (std::ops::Bound<DateTime>, std::ops::Bound<DateTime>) {
+    use std::ops::Bound::{Excluded, Unbounded};
+
+    (
+        after.map_or(Unbounded, Excluded),
+        before.map_or(Unbounded, Excluded),
+    )
+}
+
+/// Pages are plain offsets into the full result set.
+fn paginate<T>(query: &Query, items: Vec<T>) -> Result<(Vec<T>, Option<String>)> {
+    let start: usize = match &query.page {
+        Some(page) => page.parse().context("invalid page token")?,
+        None => 0,
+    };
+    let count = query.count.unwrap_or(usize::MAX);
+    let end = start.saturating_add(count);
+    let next_page = (end < items.len()).then(|| end.to_string());
+    Ok((
+        items.into_iter().skip(start).take(count).collect(),
+        next_page,
+    ))
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+const INGEST_USAGE: &str = "usage: before ingest <archive dir> versions <type> <file>...
+       before ingest <archive dir> games <file>...
+       before ingest <archive dir> site <file>...";
+
+/// Adds saved Chronicler responses to a local archive, creating it if necessary.
+///
+/// `args` are the command-line arguments following `before ingest`: the archive directory, the
+/// kind of response being ingested (`versions`, `games` or `site`), the entity type (for
+/// `versions` only), and one or more files containing `v2/versions`, `v1/games/updates` or
+/// `v1/site/updates` responses respectively. Ingesting the same data twice is harmless.
+///
+/// # Errors
+///
+/// Returns an error if the arguments are invalid, or if any of the files can't be read or parsed.
+pub fn ingest(args: &[String]) -> Result<()> {
+    let (dir, kind, rest) = match args {
+        [dir, kind, rest @ ..] => (Path::new(dir), kind.as_str(), rest),
+        _ => bail!(INGEST_USAGE),
+    };
+
+    let (path, files) = match (kind, rest) {
+        ("versions", [ty, files @ ..]) => {
+            ensure!(
+                !ty.is_empty() && ty.chars().all(|c| c.is_ascii_alphanumeric()),
+                "invalid entity type {:?}",
+                ty
+            );
+            fs::create_dir_all(dir.join("entities"))?;
+            (dir.join("entities").join(format!("{ty}.ndjson")), files)
+        }
+        ("games", files) => (dir.join("games.ndjson"), files),
+        ("site", files) => (dir.join("site.ndjson"), files),
+        _ => bail!(INGEST_USAGE),
+    };
+    ensure!(!files.is_empty(), INGEST_USAGE);
+    fs::create_dir_all(dir)?;
+
+    let mut out = BufWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+    let mut count = 0;
+    for file in files {
+        let s = fs::read_to_string(file).with_context(|| format!("failed to read {file}"))?;
+        let lines = match kind {
+            "versions" => serde_json::from_str::<Versions<Box<RawValue>>>(&s)
+                .with_context(|| format!("failed to parse {file}"))?
+                .items
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<serde_json::Result<Vec<_>>>()?,
+            "games" => serde_json::from_str::<Data<GameUpdate>>(&s)
+                .with_context(|| format!("failed to parse {file}"))?
+                .data
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<serde_json::Result<Vec<_>>>()?,
+            _ => serde_json::from_str::<Data<Box<RawValue>>>(&s)
+                .with_context(|| format!("failed to parse {file}"))?
+                .data
+                .iter()
+                .map(|update| {
+                    serde_json::from_str::<SiteUpdate>(update.get())?;
+                    Ok(update.get().to_owned())
+                })
+                .collect::<serde_json::Result<Vec<_>>>()?,
+        };
+        for line in lines {
+            writeln!(out, "{line}")?;
+            count += 1;
+        }
+    }
+    out.flush()?;
+
+    println!("ingested {count} records into {}", path.display());
+    Ok(())
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn archive_queries() {
+    use crate::chronicler::{Data, RequestBuilder};
+    use crate::time::datetime;
+    use crate::Config;
+    use rand::{thread_rng, Rng};
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Scheduled {
+        game_id: String,
+        start_time: DateTime,
+    }
+
+    let path =
+        std::env::temp_dir().join(format!("before-archive-{:016x}", thread_rng().gen::<u64>()));
+    fs::create_dir_all(path.join("entities")).unwrap();
+    fs::write(
+        path.join("entities/Stream.ndjson"),
+        [
+            r#"{"entityId":"","validFrom":"2021-03-01T00:00:08Z","data":3}"#,
+            r#"{"entityId":"","validFrom":"2021-03-01T00:00:00Z","data":1}"#,
+            "",
+            r#"{"entityId":"","validFrom":"2021-03-01T00:00:04Z","data":2}"#,
+        ]
+        .join("\n"),
+    )
+    .unwrap();
+    fs::write(
+        path.join("games.ndjson"),
+        [
+            r#"{"gameId":"b","timestamp":"2021-03-01T00:01:00Z","data":{"season":1,"day":2,"gameStart":true}}"#,
+            r#"{"gameId":"a","timestamp":"2021-03-01T00:00:00Z","data":{"season":1,"day":2}}"#,
+            r#"{"gameId":"a","timestamp":"2021-03-01T00:02:00Z","data":{"season":1,"day":2,"gameStart":true}}"#,
+            r#"{"gameId":"c","timestamp":"2021-03-01T00:00:00Z","data":{"season":1,"day":3}}"#,
+        ]
+        .join("\r\n"),
+    )
+    .unwrap();
+    let mut config = Config::default();
+    config.source = Box::new(Archive::open(&path).await.unwrap());
+
+    let entity: Versions<i64> = RequestBuilder::v2("entities")
+        .ty("Stream")
+        .at(datetime!(2021-03-01 00:00:05 UTC))
+        .json(&config)
+        .await
+        .unwrap();
+    assert_eq!(entity.items[0].data, 2);
+    assert_eq!(
+        entity.items[0].valid_to,
+        Some(datetime!(2021-03-01 00:00:08 UTC))
+    );
+
+    let past: Versions<i64> = RequestBuilder::v2("versions")
+        .ty("Stream")
+        .before(datetime!(2021-03-01 00:00:08 UTC))
+        .order(Order::Desc)
+        .count(1)
+        .json(&config)
+        .await
+        .unwrap();
+    assert_eq!(past.items.iter().map(|v| v.data).collect::<Vec<_>>(), [2]);
+    assert_eq!(past.next_page.as_deref(), Some("1"));
+
+    let games: Data<Scheduled> = RequestBuilder::v1("games")
+        .season(1)
+        .day(2)
+        .json(&config)
+        .await
+        .unwrap();
+    assert_eq!(
+        games
+            .data
+            .iter()
+            .map(|game| (game.game_id.as_str(), game.start_time))
+            .collect::<Vec<_>>(),
+        [
+            ("b", datetime!(2021-03-01 00:01:00 UTC)),
+            ("a", datetime!(2021-03-01 00:02:00 UTC)),
+        ]
+    );
+
+    fs::remove_dir_all(path).unwrap();
+}
@@ -0,0 +1,128 @@
+//! A scriptable [`DataSource`] for tests.
+
+use crate::chronicler::{Order, Query};
+use crate::source::DataSource;
+use crate::time::{datetime, DateTime, Duration};
+use anyhow::Result;
+use rocket::{async_trait, Shutdown};
+use serde_json::{json, Value};
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex};
+use std::time::Duration as StdDuration;
+
+/// When [`Fixture::stream`]'s versions start.
+pub(crate) const STREAM_START: DateTime = datetime!(2021-03-01 00:00:00 UTC);
+
+type Respond = Box<dyn Fn(&Query) -> Value + Send + Sync>;
+
+/// Answers each query with the JSON returned by a closure, and logs the queries it's asked.
+pub(crate) struct Fixture {
+    respond: Respond,
+    delay: StdDuration,
+    queries: Arc<Mutex<Vec<Query>>>,
+}
+
+impl Fixture {
+    pub(crate) fn new(respond: impl Fn(&Query) -> Value + Send + Sync + 'static) -> Fixture {
+        Fixture {
+            respond: Box::new(respond),
+            delay: StdDuration::ZERO,
+            queries: Arc::default(),
+        }
+    }
+
+    /// Answers `Stream` queries from [`stream_versions`]. Other queries are empty.
+    pub(crate) fn stream() -> Fixture {
+        let versions = stream_versions();
+        Fixture::new(move |query| {
+            if query.ty != Some("Stream") {
+                return json!({ "items": [] });
+            }
+            versions_page(query, versions.clone())
+        })
+    }
+
+    /// Waits this long before answering each query.
+    pub(crate) fn delay(self, delay: StdDuration) -> Fixture {
+        Fixture { delay, ..self }
+    }
+
+    /// The queries answered so far, in order.
+    pub(crate) fn queries(&self) -> Arc<Mutex<Vec<Query>>> {
+        self.queries.clone()
+    }
+}
+
+impl Debug for Fixture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Fixture").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl DataSource for Fixture {
+    async fn get(&self, query: &Query) -> Result<String> {
+        self.queries.lock().unwrap().push(query.clone());
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        Ok((self.respond)(query).to_string())
+    }
+}
+
+/// A `Stream` version every 5 seconds for 10 minutes from [`STREAM_START`], each with a new
+/// `games`, `leagues` and `temporal` numbered `{"n": ...}` from 0.
+pub(crate) fn stream_versions() -> Vec<(DateTime, Value)> {
+    (0..120)
+        .map(|n| {
+            let time = STREAM_START + Duration::seconds(n * 5);
+            let version = json!({
+                "entityId": "00000000-0000-0000-0000-000000000000",
+                "validFrom": time,
+                "data": { "value": {
+                    "games": { "n": n },
+                    "leagues": { "n": n },
+                    "temporal": { "doc": { "epsilon": true, "n": n } },
+                } },
+            });
+            (time, version)
+        })
+        .collect()
+}
+
+/// A shutdown handle for code that takes one, from a Rocket instance that never launches.
+pub(crate) async fn shutdown() -> Shutdown {
+    let client = rocket::local::asynchronous::Client::untracked(rocket::build())
+        .await
+        .unwrap();
+    client.rocket().shutdown()
+}
+
+/// Answers a `v2/versions` query from `versions`, sorted by time, filtering and paging them the
+/// way Chronicler does (with offsets for page tokens).
+pub(crate) fn versions_page(query: &Query, versions: Vec<(DateTime, Value)>) -> Value {
+    let mut items = versions
+        .into_iter()
+        .filter(|(time, _)| {
+            query.after.map_or(true, |after| *time > after)
+                && query.before.map_or(true, |before| *time < before)
+        })
+        .map(|(_, version)| version)
+        .collect::<Vec<_>>();
+    if let Some(Order::Desc) = query.order {
+        items.reverse();
+    }
+    let start = query
+        .page
+        .as_deref()
+        .map_or(0, |page| page.parse::<usize>().unwrap());
+    let count = query.count.unwrap_or(usize::MAX);
+    let end = start.saturating_add(count);
+    let next_page = (end < items.len()).then(|| end.to_string());
+    let items = items
+        .into_iter()
+        .skip(start)
+        .take(count)
+        .collect::<Vec<_>>();
+    json!({ "nextPage": next_page, "items": items })
+}
@@ -0,0 +1,106 @@
+//! Backends for the data Before replays.
+//!
+//! Everything Before knows about the past comes from a small subset of the Chronicler API:
+//! `v2/entities` (entities at a point in time), `v2/versions`, `v1/games`, `v1/games/updates` and
+//! `v1/site/updates`. A [`DataSource`] answers those queries, so that route code doesn't need to
+//! care whether it's talking to a live Chronicler instance or something else entirely.
+
+pub(crate) mod archive;
+#[cfg(test)]
+pub(crate) mod fixture;
+pub(crate) mod record;
+
+use crate::chronicler::Query;
+use crate::upstream::{Client, Mirrors, Priority};
+use anyhow::{anyhow, Result};
+use rocket::async_trait;
+use std::fmt::Debug;
+
+#[async_trait]
+pub(crate) trait DataSource: Debug + Send + Sync {
+    /// Answers `query` with a response body in the same JSON format Chronicler would return.
+    async fn get(&self, query: &Query) -> Result<String>;
+
+    /// Downloads a site asset, given the `downloadUrl` of a `v1/site/updates` item.
+    async fn download(&self, path: &str) -> Result<Download> {
+        Err(anyhow!(
+            "cannot download {path}: site downloads are not supported by this data source"
+        ))
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub(crate) enum Download {
+    Response(reqwest::Response),
+    Saved {
+        content_type: Option<String>,
+        body: Vec<u8>,
+    },
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[derive(Debug, Default)]
+pub(crate) struct Chronicler {
+    pub(crate) client: Client,
+    pub(crate) mirrors: Mirrors,
+}
+
+#[async_trait]
+impl DataSource for Chronicler {
+    async fn get(&self, query: &Query) -> Result<String> {
+        let path = format!(
+            "{}/{}?{}",
+            query.version,
+            query.route,
+            serde_urlencoded::to_string(query)?
+        );
+        log::debug!("chronicler request: {}", path);
+        Ok(self
+            .client
+            .get_text(&self.mirrors, &path, query.priority)
+            .await?)
+    }
+
+    async fn download(&self, path: &str) -> Result<Download> {
+        Ok(Download::Response(
+            self.client
+                .get(&self.mirrors, &format!("v1{path}"), Priority::Interactive)
+                .await?,
+        ))
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn fetch_from_fixture() {
+    use crate::source::fixture::Fixture;
+    use crate::time::datetime;
+    use crate::Config;
+    use serde_json::json;
+
+    let mut config = Config::default();
+    config.source = Box::new(Fixture::new(|query| {
+        match (query.version, query.route, query.ty) {
+            ("v2", "entities", Some("Sim")) => json!({
+                "nextPage": null,
+                "items": [{
+                    "validFrom": "2020-07-29T08:12:22.438Z",
+                    "entityId": "00000000-0000-0000-0000-000000000000",
+                    "data": { "season": 1, "day": 0 },
+                }],
+            }),
+            _ => panic!("unexpected query {query:?}"),
+        }
+    }));
+    let sim: serde_json::Value = config
+        .fetch("Sim", None, datetime!(2020-07-30 00:00:00 UTC))
+        .await
+        .unwrap()
+        .next()
+        .unwrap();
+    assert_eq!(sim, json!({ "season": 1, "day": 0 }));
+}
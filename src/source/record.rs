@@ -0,0 +1,258 @@
+//! Record-through and replay of upstream responses.
+//!
+//! In record mode, every response from the upstream data source is also saved to a
+//! content-addressed directory, keyed by a hash of the normalized query. In replay mode those saved
+//! responses are served with no upstream at all, so whatever slice of history was browsed while
+//! recording can be browsed again offline (or used as a test fixture).
+//!
+//! Replay sessions don't run at exactly the same perceived time as the recording, so a query's
+//! time (`at`, or else `before`, or else `after`) isn't part of its key. Responses are saved under
+//! the key by time, and replay answers with the latest one recorded at or before the time asked
+//! for. Replay only answers queries that were recorded; anything else is an error.
+
+use crate::chronicler::Query;
+use crate::source::{DataSource, Download};
+use crate::time::DateTime;
+use anyhow::{Context, Result};
+use rocket::async_trait;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordMode {
+    Record,
+    Replay,
+}
+
+#[derive(Debug)]
+pub(crate) struct Recording {
+    /// `None` in replay mode.
+    upstream: Option<Box<dyn DataSource>>,
+    path: PathBuf,
+}
+
+impl Recording {
+    pub(crate) async fn new(
+        mode: RecordMode,
+        upstream: Box<dyn DataSource>,
+        path: &Path,
+    ) -> Result<Recording> {
+        let upstream = match mode {
+            RecordMode::Record => {
+                fs::create_dir_all(path).await?;
+                Some(upstream)
+            }
+            RecordMode::Replay => None,
+        };
+        Ok(Recording {
+            upstream,
+            path: path.into(),
+        })
+    }
+
+    /// Reads the response saved as `name`, or if the query has a time, the latest one recorded at or
+    /// before it.
+    async fn replay(&self, key: &str, name: &str, time: Option<DateTime>) -> Option<String> {
+        let path = match time {
+            Some(time) => latest(&self.path.join(key), time).await?,
+            None => self.path.join(name),
+        };
+        fs::read_to_string(path).await.ok()
+    }
+
+    async fn save(&self, name: &str, data: &[u8]) -> Result<()> {
+        // write to a temporary file first so that a concurrent replay never sees a partial file
+        let tmp = self.path.join(format!("{name}.tmp"));
+        fs::write(&tmp, data).await?;
+        fs::rename(&tmp, self.path.join(name)).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DataSource for Recording {
+    async fn get(&self, query: &Query) -> Result<String> {
+        let (key, time) = key(query)?;
+        let name = match time {
+            Some(time) => format!("{key}/{}.json", time.unix_timestamp_millis()),
+            None => format!("{key}.json"),
+        };
+
+        match &self.upstream {
+            Some(upstream) => {
+                let body = upstream.get(query).await?;
+                // don't save error pages
+                if serde_json::from_str::<&RawValue>(&body).is_ok() {
+                    if time.is_some() {
+                        fs::create_dir_all(self.path.join(&key)).await?;
+                    }
+                    self.save(&name, body.as_bytes()).await?;
+                }
+                Ok(body)
+            }
+            None => (self.replay(&key, &name, time).await)
+                .with_context(|| format!("no recorded response for {query:?}")),
+        }
+    }
+
+    async fn download(&self, path: &str) -> Result<Download> {
+        let key = hash(&format!("v1{path}"));
+        let (body_name, type_name) = (format!("{key}.bin"), format!("{key}.type"));
+
+        match &self.upstream {
+            Some(upstream) => {
+                let (content_type, body) = match upstream.download(path).await? {
+                    Download::Response(response) => (
+                        response
+                            .headers()
+                            .get(reqwest::header::CONTENT_TYPE)
+                            .and_then(|s| s.to_str().ok())
+                            .map(String::from),
+                        response.bytes().await?.to_vec(),
+                    ),
+                    Download::Saved { content_type, body } => (content_type, body),
+                };
+                self.save(&body_name, &body).await?;
+                self.save(
+                    &type_name,
+                    content_type.as_deref().unwrap_or_default().as_bytes(),
+                )
+                .await?;
+                Ok(Download::Saved { content_type, body })
+            }
+            None => Ok(Download::Saved {
+                content_type: fs::read_to_string(self.path.join(&type_name))
+                    .await
+                    .ok()
+                    .filter(|s| !s.is_empty()),
+                body: fs::read(self.path.join(&body_name))
+                    .await
+                    .with_context(|| format!("no recorded download for {path}"))?,
+            }),
+        }
+    }
+}
+
+/// Splits a query into its time, if it has one, and a key for every query that differs from it only
+/// in that time.
+fn key(query: &Query) -> Result<(String, Option<DateTime>)> {
+    let mut rest = query.clone();
+    let (param, time) = if query.at.is_some() {
+        ("at", rest.at.take())
+    } else if query.before.is_some() {
+        ("before", rest.before.take())
+    } else {
+        ("after", rest.after.take())
+    };
+    let mut normalized = format!(
+        "{}/{}?{}",
+        query.version,
+        query.route,
+        serde_urlencoded::to_string(&rest)?
+    );
+    if time.is_some() {
+        let _ = write!(normalized, "#{param}");
+    }
+    Ok((hash(&normalized), time))
+}
+
+/// Finds the response in `dir` recorded at the latest time at or before `time`.
+async fn latest(dir: &Path, time: DateTime) -> Option<PathBuf> {
+    let time = time.unix_timestamp_millis();
+    let mut latest = None;
+    let mut entries = fs::read_dir(dir).await.ok()?;
+    while let Some(entry) = entries.next_entry().await.ok()? {
+        let path = entry.path();
+        if path.extension().map_or(true, |ext| ext != "json") {
+            continue;
+        }
+        let recorded = match path
+            .file_stem()
+            .and_then(|s| s.to_str()?.parse::<i128>().ok())
+        {
+            Some(recorded) if recorded <= time => recorded,
+            _ => continue,
+        };
+        if latest.as_ref().map_or(true, |(t, _)| recorded > *t) {
+            latest = Some((recorded, path));
+        }
+    }
+    latest.map(|(_, path)| path)
+}
+
+fn hash(normalized: &str) -> String {
+    Sha256::digest(normalized.as_bytes())
+        .iter()
+        .fold(String::with_capacity(64), |mut s, b| {
+            let _ = write!(s, "{b:02x}");
+            s
+        })
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn record_then_replay() {
+    use crate::chronicler::{RequestBuilder, Versions};
+    use crate::source::fixture::Fixture;
+    use crate::time::datetime;
+    use crate::Config;
+    use serde_json::json;
+
+    let fixture = || {
+        Box::new(Fixture::new(|_| {
+            json!({
+                "nextPage": null,
+                "items": [{ "validFrom": "2020-07-29T08:12:22.438Z", "entityId": "", "data": 4 }],
+            })
+        }))
+    };
+    let path = std::env::temp_dir().join(format!("before-record-{}", std::process::id()));
+    let request = RequestBuilder::v2("entities")
+        .ty("Sim")
+        .at(datetime!(2020-07-30 00:00:00 UTC));
+
+    let mut config = Config::default();
+    config.source = Box::new(
+        Recording::new(RecordMode::Record, fixture(), &path)
+            .await
+            .unwrap(),
+    );
+    let recorded: Versions<i64> = request.clone().json(&config).await.unwrap();
+
+    config.source = Box::new(
+        Recording::new(RecordMode::Replay, fixture(), &path)
+            .await
+            .unwrap(),
+    );
+    let replayed: Versions<i64> = request.json(&config).await.unwrap();
+    assert_eq!(recorded.items[0].data, replayed.items[0].data);
+    // a replay running later than the recording gets the latest response recorded before it, but
+    // there's nothing recorded for earlier
+    let later: Versions<i64> = RequestBuilder::v2("entities")
+        .ty("Sim")
+        .at(datetime!(2020-07-30 00:13:37.123 UTC))
+        .json(&config)
+        .await
+        .unwrap();
+    assert_eq!(recorded.items[0].data, later.items[0].data);
+    let earlier: Result<Versions<i64>> = RequestBuilder::v2("entities")
+        .ty("Sim")
+        .at(datetime!(2020-07-29 00:00:00 UTC))
+        .json(&config)
+        .await;
+    assert!(earlier.is_err());
+    let missing: Result<Versions<i64>> = RequestBuilder::v2("entities")
+        .ty("Team")
+        .json(&config)
+        .await;
+    assert!(missing.is_err());
+
+    std::fs::remove_dir_all(path).unwrap();
+}
@@ -0,0 +1,261 @@
+//! Shared schedulers for identical replay streams.
+//!
+//! Clients viewing the same timeline (the same offset and playback rate) starting within the same
+//! cache bucket would all sleep until the same versions' `valid_from` times. Instead, the
+//! first of them spawns a scheduler for that (bucket, timeline) pair, which sends each update to
+//! every subscribed stream over a broadcast channel. Each stream still builds its own
+//! `Item::Start`, and skips updates that were already part of it.
+//!
+//! A scheduler ends when it runs out of events or subscribers, and is removed from
+//! [`Broadcaster`] when it does.
+
+use crate::chronicler::Version;
+use crate::config::Config;
+use crate::offset::Clock;
+use crate::stream::{cache_bucket, Item, StreamEvent, STREAM_LENGTH};
+use crate::time::DateTime;
+use rocket::Shutdown;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio::time::{sleep, Instant};
+
+/// How many updates a subscriber can fall behind before it starts missing them.
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    bucket: DateTime,
+    offset: i64,
+    rate: u64,
+    anchor: DateTime,
+}
+
+impl Key {
+    fn new(bucket: DateTime, clock: &Clock) -> Key {
+        // at 1x, the anchor doesn't affect the perceived time
+        let anchor = if clock.playback.is_realtime() {
+            DateTime::new(time::OffsetDateTime::UNIX_EPOCH)
+        } else {
+            clock.playback.anchor
+        };
+        Key {
+            bucket,
+            offset: clock.offset.0.whole_seconds(),
+            rate: clock.playback.rate.to_bits(),
+            anchor,
+        }
+    }
+}
+
+type Sender = Arc<broadcast::Sender<Item>>;
+
+#[derive(Debug, Default)]
+pub(crate) struct Broadcaster {
+    schedulers: Mutex<HashMap<Key, Sender>>,
+}
+
+impl Broadcaster {
+    /// The number of running schedulers, i.e. distinct timelines being streamed.
+    pub(crate) fn len(&self) -> usize {
+        self.schedulers.lock().unwrap().len()
+    }
+
+    /// Subscribes to updates after the current perceived time in bucket `bucket`, spawning a
+    /// scheduler for the timeline if there isn't one yet. `events` are the bucket's events.
+    pub(crate) fn subscribe(
+        &self,
+        config: &Arc<Config>,
+        bucket: DateTime,
+        clock: Clock,
+        events: &[Arc<Version<StreamEvent>>],
+        shutdown: Shutdown,
+    ) -> broadcast::Receiver<Item> {
+        let key = Key::new(bucket, &clock);
+        let mut schedulers = self.schedulers.lock().unwrap();
+        if let Some(tx) = schedulers.get(&key) {
+            return tx.subscribe();
+        }
+
+        let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
+        let tx = Arc::new(tx);
+        schedulers.insert(key, tx.clone());
+        let time = clock.now();
+        let future = events
+            .iter()
+            .filter(|event| event.valid_from > time)
+            .cloned()
+            .collect();
+        tokio::spawn(schedule(
+            config.clone(),
+            key,
+            clock,
+            time,
+            future,
+            tx,
+            shutdown,
+        ));
+        rx
+    }
+
+    fn remove(&self, key: &Key, tx: &Sender) {
+        let mut schedulers = self.schedulers.lock().unwrap();
+        if schedulers.get(key).map_or(false, |v| Arc::ptr_eq(v, tx)) {
+            schedulers.remove(key);
+        }
+    }
+}
+
+async fn schedule(
+    config: Arc<Config>,
+    key: Key,
+    clock: Clock,
+    time: DateTime,
+    mut future: Vec<Arc<Version<StreamEvent>>>,
+    tx: Sender,
+    mut shutdown: Shutdown,
+) {
+    // Each cache bucket only has a couple minutes of events, which isn't enough to last until the
+    // frontend reopens the stream if time is passing faster than usual. In that case, keep loading
+    // later buckets until enough wall-clock time has passed.
+    let deadline = Instant::now() + STREAM_LENGTH;
+    let mut last = time;
+    'outer: loop {
+        for version in future {
+            let duration = clock
+                .wall_duration(version.valid_from - clock.now())
+                .unwrap_or(STREAM_LENGTH);
+            select! {
+                () = sleep(duration) => {},
+                () = &mut shutdown => break 'outer,
+            }
+            last = version.valid_from;
+            if tx.send(Item::Update(version)).is_err() {
+                // everyone has left
+                break 'outer;
+            }
+        }
+
+        if clock.playback.current() <= 1.0 || Instant::now() >= deadline {
+            break;
+        }
+        let next = match last.trunc(config.stream_bucket()) {
+            Ok(bucket) => cache_bucket(&config, bucket).await,
+            Err(err) => Err(err),
+        };
+        future = match next {
+            Ok((_, events)) => events
+                .into_iter()
+                .filter(|event| event.valid_from > last)
+                .collect(),
+            Err(err) => {
+                log::warn!("failed to load stream events after {last}: {err:#}");
+                break;
+            }
+        };
+        if future.is_empty() {
+            break;
+        }
+    }
+    config.broadcaster.remove(&key, &tx);
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+fn test_events(times: &[DateTime]) -> Vec<Arc<Version<StreamEvent>>> {
+    use crate::stream::StreamValue;
+    use serde_json::value::RawValue;
+
+    (times.iter().enumerate())
+        .map(|(n, time)| {
+            Arc::new(Version {
+                valid_from: *time,
+                valid_to: None,
+                entity_id: "stream".into(),
+                data: StreamEvent::new(StreamValue {
+                    games: Some(Arc::from(RawValue::from_string(n.to_string()).unwrap())),
+                    leagues: None,
+                    temporal: None,
+                    fights: None,
+                }),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn fan_out_to_shared_timeline() {
+    use crate::offset::{Offset, Playback};
+    use time::Duration;
+
+    let config = Arc::new(Config::default());
+    let shutdown = crate::source::fixture::shutdown().await;
+    let clock = |offset| Clock {
+        offset: Offset(Duration::seconds(offset)),
+        playback: Playback::default(),
+    };
+    let now = DateTime::now();
+    let bucket = now.trunc(config.stream_bucket()).unwrap();
+    let events = test_events(&[
+        now + Duration::milliseconds(100),
+        now + Duration::milliseconds(200),
+    ]);
+
+    let mut a =
+        (config.broadcaster).subscribe(&config, bucket, clock(0), &events, shutdown.clone());
+    let mut b =
+        (config.broadcaster).subscribe(&config, bucket, clock(0), &events, shutdown.clone());
+    assert_eq!(config.broadcaster.len(), 1);
+    // a different timeline gets its own scheduler
+    let c = (config.broadcaster).subscribe(&config, bucket, clock(60), &events, shutdown.clone());
+    assert_eq!(config.broadcaster.len(), 2);
+    drop(c);
+
+    for event in &events {
+        for rx in [&mut a, &mut b] {
+            match rx.recv().await.unwrap() {
+                Item::Update(version) => assert!(Arc::ptr_eq(&version, event)),
+                Item::Start(..) => panic!("scheduler sent a snapshot"),
+            }
+        }
+    }
+    // out of events, leaving the other timeline's scheduler waiting for its first update
+    assert!(a.recv().await.is_err());
+    assert_eq!(config.broadcaster.len(), 1);
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn scheduler_stops_when_subscribers_leave() {
+    use crate::offset::{Offset, Playback};
+    use std::time::Duration as StdDuration;
+    use time::Duration;
+
+    let config = Arc::new(Config::default());
+    let clock = Clock {
+        offset: Offset(Duration::ZERO),
+        playback: Playback::default(),
+    };
+    let now = DateTime::now();
+    let bucket = now.trunc(config.stream_bucket()).unwrap();
+    let events = test_events(&[
+        now + Duration::milliseconds(100),
+        now + Duration::minutes(1),
+    ]);
+
+    let rx = (config.broadcaster).subscribe(
+        &config,
+        bucket,
+        clock,
+        &events,
+        crate::source::fixture::shutdown().await,
+    );
+    assert_eq!(config.broadcaster.len(), 1);
+    drop(rx);
+    // the scheduler notices when it next has an update to send, well before it runs out
+    sleep(StdDuration::from_millis(500)).await;
+    assert_eq!(config.broadcaster.len(), 0);
+}
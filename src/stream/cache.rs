@@ -0,0 +1,192 @@
+//! The in-memory stream cache, and interning of stream components.
+//!
+//! Neighbouring cache buckets share most of their events, and `leagues` and `temporal` rarely
+//! change, so the same blobs would otherwise be stored many times over. [`intern`] returns a
+//! shared `Arc` for every blob with the same content, and [`StreamCache`] charges each distinct
+//! blob against its byte budget only once, no matter how many entries hold it.
+
+use crate::stream::{StreamCacheValue, StreamValue};
+use crate::time::DateTime;
+use lru::LruCache;
+use serde_json::value::RawValue;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem::size_of;
+use std::sync::{Arc, Mutex, Weak};
+
+lazy_static::lazy_static! {
+    static ref INTERNER: Mutex<Interner> = Mutex::new(Interner::default());
+}
+
+#[derive(Default)]
+struct Interner {
+    blobs: HashMap<u64, Vec<Weak<RawValue>>>,
+    /// Blobs added since dropped ones were last swept out.
+    added: usize,
+}
+
+/// Returns a blob with the same content as `value`, shared with any other live blob interned with
+/// that content.
+pub(crate) fn intern(value: Arc<RawValue>) -> Arc<RawValue> {
+    let mut hasher = DefaultHasher::new();
+    value.get().hash(&mut hasher);
+    let hash = hasher.finish();
+
+    let mut interner = INTERNER.lock().unwrap();
+    let candidates = interner.blobs.entry(hash).or_default();
+    if let Some(existing) = candidates
+        .iter()
+        .filter_map(Weak::upgrade)
+        .find(|existing| existing.get() == value.get())
+    {
+        return existing;
+    }
+    candidates.push(Arc::downgrade(&value));
+
+    interner.added += 1;
+    if interner.added > interner.blobs.len() {
+        interner.blobs.retain(|_, candidates| {
+            candidates.retain(|weak| weak.strong_count() > 0);
+            !candidates.is_empty()
+        });
+        interner.added = 0;
+    }
+    value
+}
+
+pub(crate) fn intern_value(value: &mut StreamValue) {
+    for component in [
+        &mut value.games,
+        &mut value.leagues,
+        &mut value.temporal,
+        &mut value.fights,
+    ] {
+        *component = component.take().map(intern);
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// An LRU cache of stream buckets, bounded by the bytes of distinct data its entries hold.
+#[derive(Debug)]
+pub(crate) struct StreamCache {
+    entries: LruCache<DateTime, StreamCacheValue>,
+    max_bytes: usize,
+    bytes: usize,
+    /// Reference counts and sizes of every blob held by an entry, keyed by address.
+    blobs: HashMap<usize, (usize, usize)>,
+}
+
+impl StreamCache {
+    pub(crate) fn new(max_bytes: usize) -> StreamCache {
+        StreamCache {
+            entries: LruCache::unbounded(),
+            max_bytes,
+            bytes: 0,
+            blobs: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub(crate) fn get(&mut self, key: &DateTime) -> Option<&StreamCacheValue> {
+        self.entries.get(key)
+    }
+
+    pub(crate) fn put(&mut self, key: DateTime, value: StreamCacheValue) {
+        if self.entries.contains(&key) {
+            return;
+        }
+        for (address, len) in blobs(&value) {
+            let (refs, _) = self.blobs.entry(address).or_insert_with(|| {
+                self.bytes += len;
+                (0, len)
+            });
+            *refs += 1;
+        }
+        self.entries.put(key, value);
+
+        // always keep the newest entry, even if it alone is over budget
+        while self.bytes > self.max_bytes && self.entries.len() > 1 {
+            let (_, evicted) = match self.entries.pop_lru() {
+                Some(x) => x,
+                None => break,
+            };
+            for (address, _) in blobs(&evicted) {
+                if let Entry::Occupied(mut entry) = self.blobs.entry(address) {
+                    entry.get_mut().0 -= 1;
+                    if entry.get().0 == 0 {
+                        self.bytes -= entry.remove().1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Lists the addresses and approximate sizes of the separately-allocated parts of an entry.
+fn blobs(value: &StreamCacheValue) -> Vec<(usize, usize)> {
+    fn raw(blob: &Arc<RawValue>) -> (usize, usize) {
+        (Arc::as_ptr(blob).cast::<u8>() as usize, blob.get().len())
+    }
+
+    let (first, events) = value;
+    let mut blobs = Vec::with_capacity(4 + events.len() * 5);
+    blobs.push(raw(&first.games));
+    blobs.push(raw(&first.leagues));
+    blobs.push(raw(&first.temporal));
+    blobs.extend(first.fights.as_ref().map(raw));
+    for event in events {
+        blobs.push((
+            Arc::as_ptr(event) as usize,
+            size_of::<crate::chronicler::Version<()>>() + event.entity_id.len(),
+        ));
+        let value = &event.data.value;
+        blobs.extend(
+            [&value.games, &value.leagues, &value.temporal, &value.fights]
+                .into_iter()
+                .flatten()
+                .map(raw),
+        );
+    }
+    blobs
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[test]
+fn shared_blobs_count_once() {
+    use crate::stream::First;
+    use crate::time::datetime;
+
+    let blob = |s: &str| intern(Arc::from(RawValue::from_string(s.into()).unwrap()));
+    let first = |games: &str| First {
+        games: blob(games),
+        leagues: blob("\"leagues\""),
+        temporal: blob("\"temporal\""),
+        fights: None,
+        sources: Box::default(),
+    };
+    let a = datetime!(2021-03-01 00:00:00 UTC);
+    let b = datetime!(2021-03-01 00:00:15 UTC);
+    let c = datetime!(2021-03-01 00:00:30 UTC);
+
+    let mut cache = StreamCache::new(40);
+    cache.put(a, (first("\"aaaaaaaaaa\""), Vec::new()));
+    assert_eq!(cache.bytes(), 12 + 9 + 10);
+    // only the new `games` blob is charged, which goes over budget and evicts `a`
+    cache.put(b, (first("\"bbbbbbbbbb\""), Vec::new()));
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.bytes(), 12 + 9 + 10);
+    cache.put(c, (first("\"bbbbbbbbbb\""), Vec::new()));
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.bytes(), 12 + 9 + 10);
+}
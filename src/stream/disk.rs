@@ -0,0 +1,230 @@
+//! An optional on-disk tier for the stream cache, so that warm buckets survive restarts and can be
+//! shared between instances through a common volume.
+//!
+//! Each bucket is stored as one JSON file, named after the bucket's Unix timestamp and a hash of
+//! the settings that shape buckets, holding the serialized `First` and the future events. Instances
+//! (or restarts) with different settings don't see each other's buckets. Files are written to a temporary name and renamed
+//! into place, so readers (including other instances) never see a partial file. When the
+//! directory grows past its byte budget, the oldest-written files are removed first.
+
+use crate::chronicler::Version;
+use crate::stream::cache::{intern, intern_value};
+use crate::stream::{First, Sources, StreamCacheValue, StreamEvent};
+use crate::time::DateTime;
+use anyhow::Result;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs;
+
+/// Bumped whenever the file format changes, so that old files are ignored rather than misread.
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub(crate) struct DiskCache {
+    path: PathBuf,
+    max_bytes: u64,
+    /// A hash of the settings the buckets were built with.
+    settings: u64,
+    /// An estimate of the directory's size; other instances may be writing to it too.
+    size: AtomicU64,
+    evicting: AtomicBool,
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    first: &'a First,
+    sources: &'a Sources,
+    events: &'a [Arc<Version<StreamEvent>>],
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    first: First,
+    #[serde(default)]
+    sources: Box<Sources>,
+    events: Vec<Version<StreamEvent>>,
+}
+
+impl DiskCache {
+    pub(crate) async fn new(path: &Path, max_bytes: u64, settings: impl Hash) -> Result<DiskCache> {
+        fs::create_dir_all(path).await?;
+        let mut hasher = DefaultHasher::new();
+        settings.hash(&mut hasher);
+        let cache = DiskCache {
+            path: path.into(),
+            max_bytes,
+            settings: hasher.finish(),
+            size: AtomicU64::new(0),
+            evicting: AtomicBool::new(false),
+        };
+        let size = cache.entries().await?.iter().map(|(_, len, _)| len).sum();
+        cache.size.store(size, Ordering::Relaxed);
+        Ok(cache)
+    }
+
+    fn file_name(&self, bucket: DateTime) -> String {
+        format!(
+            "v{FORMAT_VERSION}-{:016x}-{}.json",
+            self.settings,
+            bucket.0.unix_timestamp()
+        )
+    }
+
+    /// Returns the bucket if it is on disk. Read and parse errors are treated as misses.
+    pub(crate) async fn get(&self, bucket: DateTime) -> Option<StreamCacheValue> {
+        let path = self.path.join(self.file_name(bucket));
+        let data = match fs::read(&path).await {
+            Ok(data) => data,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("failed to read {}: {err}", path.display());
+                }
+                return None;
+            }
+        };
+        let entry: Entry = match serde_json::from_slice(&data) {
+            Ok(entry) => entry,
+            Err(err) => {
+                log::warn!("failed to parse {}: {err}", path.display());
+                return None;
+            }
+        };
+        let first = First {
+            games: intern(entry.first.games),
+            leagues: intern(entry.first.leagues),
+            temporal: intern(entry.first.temporal),
+            fights: entry.first.fights.map(intern),
+            sources: entry.sources,
+        };
+        let events = entry
+            .events
+            .into_iter()
+            .map(|mut event| {
+                intern_value(&mut event.data.value);
+                Arc::new(event)
+            })
+            .collect();
+        Some((first, events))
+    }
+
+    pub(crate) async fn put(&self, bucket: DateTime, value: &StreamCacheValue) -> Result<()> {
+        let data = serde_json::to_vec(&EntryRef {
+            first: &value.0,
+            sources: &value.0.sources,
+            events: &value.1,
+        })?;
+        let name = self.file_name(bucket);
+        let tmp = self
+            .path
+            .join(format!(".{name}.{:016x}.tmp", thread_rng().gen::<u64>()));
+        fs::write(&tmp, &data).await?;
+        let path = self.path.join(name);
+        // the bucket may already be on disk (written by another instance, or before it expired
+        // from memory), in which case it's replaced rather than added
+        let replaced = fs::metadata(&path)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        fs::rename(&tmp, path).await?;
+
+        let len = data.len() as u64;
+        let update = |size: u64| size.saturating_sub(replaced) + len;
+        let size = update(
+            self.size
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |size| {
+                    Some(update(size))
+                })
+                .unwrap_or_else(|size| size),
+        );
+        if size > self.max_bytes && !self.evicting.swap(true, Ordering::AcqRel) {
+            let result = self.evict().await;
+            self.evicting.store(false, Ordering::Release);
+            result?;
+        }
+        Ok(())
+    }
+
+    async fn evict(&self) -> Result<()> {
+        let mut entries = self.entries().await?;
+        entries.sort_by_key(|(modified, _, _)| *modified);
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in entries {
+            if size <= self.max_bytes {
+                break;
+            }
+            match fs::remove_file(&path).await {
+                Ok(()) => size -= len,
+                // another instance got to it first
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => size -= len,
+                Err(err) => log::warn!("failed to remove {}: {err}", path.display()),
+            }
+        }
+        self.size.store(size, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Lists the cache files in the directory, with their modification times and sizes.
+    async fn entries(&self) -> Result<Vec<(SystemTime, u64, PathBuf)>> {
+        let mut entries = Vec::new();
+        let mut dir = fs::read_dir(&self.path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            entries.push((metadata.modified()?, metadata.len(), path));
+        }
+        Ok(entries)
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn round_trip_and_evict() {
+    use crate::time::datetime;
+    use serde_json::value::RawValue;
+
+    let raw = |s: &str| -> Arc<RawValue> { Arc::from(RawValue::from_string(s.into()).unwrap()) };
+    let path = std::env::temp_dir().join(format!("before-test-{:016x}", thread_rng().gen::<u64>()));
+    let first = First {
+        games: raw("{\"g\":1}"),
+        leagues: raw("{\"l\":1}"),
+        temporal: raw("{\"t\":1}"),
+        fights: None,
+        sources: Box::default(),
+    };
+    let a = datetime!(2021-03-01 00:00:00 UTC);
+    let b = datetime!(2021-03-01 00:00:15 UTC);
+
+    let cache = DiskCache::new(&path, 150, 15).await.unwrap();
+    cache.put(a, &(first.clone(), Vec::new())).await.unwrap();
+    let size = cache.size.load(Ordering::Relaxed);
+    // rewriting a bucket replaces its size in the estimate
+    cache.put(a, &(first.clone(), Vec::new())).await.unwrap();
+    assert_eq!(cache.size.load(Ordering::Relaxed), size);
+    let (loaded, events) = cache.get(a).await.unwrap();
+    assert_eq!(
+        serde_json::to_string(&loaded).unwrap(),
+        serde_json::to_string(&first).unwrap()
+    );
+    assert!(events.is_empty());
+    // buckets built with other settings aren't shared
+    let other = DiskCache::new(&path, 150, 30).await.unwrap();
+    assert!(other.get(a).await.is_none());
+
+    // each entry is about 100 bytes, so writing a second one goes over budget
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    cache.put(b, &(first, Vec::new())).await.unwrap();
+    assert!(cache.get(a).await.is_none());
+    assert!(cache.get(b).await.is_some());
+
+    tokio::fs::remove_dir_all(&path).await.unwrap();
+}
@@ -4,16 +4,21 @@ use crate::stream::postseason::Postseason;
 use crate::stream::StreamEvent;
 use crate::time::DateTime;
 use anyhow::Result;
+use itertools::Itertools;
 use rocket::futures::future::{try_join_all, FutureExt};
+use rocket::futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::OnceCell;
 use tokio::try_join;
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
 pub(crate) enum Games {
-    Value(Box<RawValue>),
+    Value(Arc<RawValue>),
     Constructed {
         schedule: Vec<Box<RawValue>>,
         tomorrow_schedule: Vec<Box<RawValue>>,
@@ -64,7 +69,13 @@ impl Games {
         if let Some(ref sim) = sim {
             let sim: Sim = serde_json::from_str(sim.get())?;
 
-            if !config.chronplete {
+            if config.chronplete {
+                let (today, tomorrow) =
+                    fetch_games_chronplete(config, sim.season, sim.tournament, sim.day, time)
+                        .await?;
+                schedule = today;
+                tomorrow_schedule = tomorrow;
+            } else {
                 let (today_ids, tomorrow_ids) = try_join!(
                     fetch_game_ids(config, sim.season, sim.tournament, sim.day),
                     fetch_game_ids(config, sim.season, sim.tournament, sim.day + 1),
@@ -188,6 +199,100 @@ struct Tournament {
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
+/// Every game's ID by its season, tournament and day, for data sources that have complete entity
+/// history but not `v1/games` or `v1/games/updates`.
+///
+/// Games don't move once they're scheduled, so this is built once from the latest version of every
+/// `Game`, and only rebuilt to look up a time after that.
+#[derive(Debug)]
+pub(crate) struct GameIndex {
+    built: DateTime,
+    days: HashMap<(i64, i64, i64), Vec<String>>,
+}
+
+/// The current [`GameIndex`], or the one being built, so that concurrent lookups wait for a single
+/// build instead of all building it (see [`StreamFlights`](crate::stream::StreamFlights)).
+pub(crate) type GameIndexFlight = StdMutex<Arc<OnceCell<Arc<GameIndex>>>>;
+
+impl GameIndex {
+    async fn get(config: &Config, time: DateTime) -> Result<Arc<GameIndex>> {
+        let flight = {
+            let mut flight = config.game_index.lock().unwrap();
+            if flight.get().map_or(false, |index| index.built < time) {
+                *flight = Arc::default();
+            }
+            flight.clone()
+        };
+        // if the first caller fails, the next one waiting takes over
+        Ok(flight
+            .get_or_try_init(|| GameIndex::build(config))
+            .await?
+            .clone())
+    }
+
+    async fn build(config: &Config) -> Result<Arc<GameIndex>> {
+        #[derive(Debug, Deserialize)]
+        struct Game {
+            season: i64,
+            day: i64,
+            #[serde(default = "default_tournament")]
+            tournament: i64,
+        }
+
+        let built = DateTime::now();
+        let games: Vec<Version<Game>> = RequestBuilder::v2("entities")
+            .ty("Game")
+            .at(built)
+            .paged_json(config)
+            .try_collect()
+            .await?;
+        let mut days = HashMap::<_, Vec<_>>::new();
+        for game in games {
+            let key = (game.data.season, game.data.tournament, game.data.day);
+            days.entry(key).or_default().push(game.entity_id);
+        }
+        // `v1/games` orders games by start time, breaking ties by ID (see `Archive::games`). Every
+        // game on a day is started by the same sim tick, and so shows up as started in the same
+        // stream update, so only the ID tells them apart.
+        for ids in days.values_mut() {
+            ids.sort_unstable();
+        }
+        log::info!("indexed {} days of games", days.len());
+        Ok(Arc::new(GameIndex { built, days }))
+    }
+}
+
+/// Finds today's and tomorrow's games at `time` with [`GameIndex`].
+async fn fetch_games_chronplete(
+    config: &Config,
+    season: i64,
+    tournament: i64,
+    day: i64,
+    time: DateTime,
+) -> Result<(Vec<Box<RawValue>>, Vec<Box<RawValue>>)> {
+    let index = GameIndex::get(config, time).await?;
+    // as in `fetch_game_ids`, tournament games aren't part of a season
+    let season = if tournament == -1 { season } else { -1 };
+    let ids = |day| (index.days.get(&(season, tournament, day))).map_or(&[][..], Vec::as_slice);
+    let (today, tomorrow) = (ids(day), ids(day + 1));
+    if today.is_empty() && tomorrow.is_empty() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+
+    // games scheduled after `time` aren't returned
+    let mut games = config
+        .fetch_versions::<Box<RawValue>>("Game", Some(today.iter().chain(tomorrow).join(",")), time)
+        .await?
+        .map(|version| (version.entity_id, version.data))
+        .collect::<HashMap<_, _>>();
+    let mut take = |ids: &[String]| {
+        ids.iter()
+            .filter_map(|id| games.remove(id))
+            .collect::<Vec<_>>()
+    };
+    Ok((take(today), take(tomorrow)))
+}
+
 async fn fetch_game_ids(
     config: &Config,
     season: i64,
@@ -223,3 +328,128 @@ async fn fetch_game_ids(
         })
         .collect())
 }
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn chronplete_schedule() {
+    use crate::source::fixture::Fixture;
+    use crate::time::datetime;
+    use serde_json::json;
+
+    let games = [
+        ("b", 2, 1),
+        ("a", 2, 1),
+        ("c", 2, 2),
+        ("d", 3, 1),
+        ("e", 2, 3),
+    ];
+    let fixture = Fixture::new(move |query| {
+        let items = (games.iter())
+            .filter(|(id, ..)| {
+                query
+                    .id
+                    .as_deref()
+                    .map_or(true, |ids| ids.split(',').any(|i| i == *id))
+            })
+            .map(|(id, season, day)| {
+                json!({
+                    "entityId": id,
+                    "validFrom": "2021-03-01T00:00:00Z",
+                    "data": { "id": id, "season": season, "day": day },
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "items": items })
+    });
+    let fixture = fixture.delay(std::time::Duration::from_millis(50));
+    let queries = fixture.queries();
+    let mut config = Config::default();
+    config.source = Box::new(fixture);
+
+    // concurrent lookups share one build
+    let time = datetime!(2021-03-01 01:00:00 UTC);
+    let (a, b) = try_join!(GameIndex::get(&config, time), GameIndex::get(&config, time)).unwrap();
+    assert!(Arc::ptr_eq(&a, &b));
+
+    let ids = |games: Vec<Box<RawValue>>| {
+        (games.iter())
+            .map(|game| {
+                serde_json::from_str::<serde_json::Value>(game.get()).unwrap()["id"].clone()
+            })
+            .collect::<Vec<_>>()
+    };
+    for day in [1, 2] {
+        let (today, tomorrow) =
+            fetch_games_chronplete(&config, 2, -1, day, datetime!(2021-03-01 01:00:00 UTC))
+                .await
+                .unwrap();
+        if day == 1 {
+            assert_eq!(
+                (ids(today), ids(tomorrow)),
+                (vec![json!("a"), json!("b")], vec![json!("c")])
+            );
+        } else {
+            assert_eq!(
+                (ids(today), ids(tomorrow)),
+                (vec![json!("c")], vec![json!("e")])
+            );
+        }
+    }
+    // the index is only built once, and each schedule only asks for its own games
+    let queries = queries.lock().unwrap();
+    let ids = queries.iter().map(|q| q.id.as_deref()).collect::<Vec<_>>();
+    assert_eq!(ids, [None, Some("a,b,c"), Some("c,e")]);
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn chronplete_matches_v1_games() {
+    use crate::source::archive::Archive;
+    use crate::time::datetime;
+    use rand::{thread_rng, Rng};
+    use std::fs;
+
+    // an archive with both `v1/games` and `Game` entities, where a day's games start together
+    let path =
+        std::env::temp_dir().join(format!("before-games-{:016x}", thread_rng().gen::<u64>()));
+    fs::create_dir_all(path.join("entities")).unwrap();
+    let ids = ["d", "a", "c", "b"];
+    let line = |id: &str, time: &str, started: bool| {
+        format!(
+            r#"{{"gameId":"{id}","timestamp":"{time}","data":{{"id":"{id}","season":2,"day":1,"gameStart":{started}}}}}"#
+        )
+    };
+    let games = (ids.iter())
+        .flat_map(|id| {
+            [
+                line(id, "2021-03-01T00:00:00Z", false),
+                line(id, "2021-03-01T01:00:00Z", true),
+            ]
+        })
+        .collect::<Vec<_>>();
+    fs::write(path.join("games.ndjson"), games.join("\n")).unwrap();
+    let entities = (ids.iter())
+        .map(|id| {
+            format!(
+                r#"{{"entityId":"{id}","validFrom":"2021-03-01T01:00:00Z","data":{{"id":"{id}","season":2,"day":1}}}}"#
+            )
+        })
+        .collect::<Vec<_>>();
+    fs::write(path.join("entities/Game.ndjson"), entities.join("\n")).unwrap();
+    let mut config = Config::default();
+    config.source = Box::new(Archive::open(&path).await.unwrap());
+
+    let v1 = fetch_game_ids(&config, 2, -1, 1).await.unwrap();
+    let (today, _) = fetch_games_chronplete(&config, 2, -1, 1, datetime!(2021-03-01 01:00:00 UTC))
+        .await
+        .unwrap();
+    let chronplete = (today.iter())
+        .map(|game| serde_json::from_str::<serde_json::Value>(game.get()).unwrap()["id"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(v1, ["a", "b", "c", "d"]);
+    assert_eq!(chronplete, v1);
+
+    fs::remove_dir_all(path).unwrap();
+}
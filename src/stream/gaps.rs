@@ -0,0 +1,332 @@
+//! Reconstruction of the stream across gaps in Chronicler's `Stream` versions.
+//!
+//! When Chronicler wasn't collecting the stream for a while, consecutive versions can be minutes
+//! or hours apart, and a replay would freeze on the last one until the next. Game updates, `Sim`
+//! and `Temporal` were often still collected separately, so for the part of a gap around a cache
+//! bucket, we synthesize events from them: the `games` component is rebuilt from the last one
+//! before the gap, with each scheduled game and the sim replaced by their state at the time. Other
+//! components are left as they were.
+//!
+//! Synthesized events are marked with `"synthesized": true` next to their `value`.
+
+use crate::chronicler::{Order, RequestBuilder, Version};
+use crate::config::Config;
+use crate::stream::{StreamEvent, StreamValue};
+use crate::time::{DateTime, Duration};
+use anyhow::Result;
+use rocket::futures::future::try_join_all;
+use rocket::futures::TryStreamExt;
+use serde::Deserialize;
+use serde_json::value::{to_raw_value, RawValue};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::try_join;
+
+/// How far before and after the cache bucket events are synthesized.
+const SPAN: Duration = Duration::minutes(2);
+
+/// Adds synthesized events to `events`, which must be sorted, for gaps longer than
+/// `stream_gap_secs` near `cache_time`.
+pub(super) async fn fill(
+    config: &Config,
+    events: &mut Vec<Version<StreamEvent>>,
+    cache_time: DateTime,
+) {
+    if config.stream_gap_secs == 0 {
+        return;
+    }
+    let (start, end) = (cache_time - SPAN, cache_time + SPAN);
+
+    let mut synthesized = Vec::new();
+    for (i, pair) in events.windows(2).enumerate() {
+        let base = events[..=i]
+            .iter()
+            .rev()
+            .find_map(|v| v.data.value.games.as_deref());
+        let (from, to) = (pair[0].valid_from, pair[1].valid_from);
+        synthesized.extend(fill_gap(config, base, from, to, start, end).await);
+    }
+
+    if !synthesized.is_empty() {
+        events.extend(synthesized);
+        events.sort_by_key(|v| v.valid_from);
+    }
+}
+
+/// Returns synthesized events for the part between `start` and `end` of the gap between
+/// consecutive versions at `from` and `to`, if it's longer than `stream_gap_secs`. `base` is the
+/// last `games` component before the gap.
+pub(super) async fn fill_gap(
+    config: &Config,
+    base: Option<&RawValue>,
+    from: DateTime,
+    to: DateTime,
+    start: DateTime,
+    end: DateTime,
+) -> Vec<Version<StreamEvent>> {
+    let threshold = Duration::seconds(config.stream_gap_secs.try_into().unwrap_or(i64::MAX));
+    if config.stream_gap_secs == 0 || to - from <= threshold || to <= start || from >= end {
+        return Vec::new();
+    }
+    let base = if let Some(base) = base {
+        base
+    } else {
+        log::debug!("no games before stream gap at {from}, not reconstructing it");
+        return Vec::new();
+    };
+    // this is best-effort, so a failure shouldn't fail the whole stream
+    match synthesize(config, base, from, from.max(start), to.min(end)).await {
+        Ok(events) => events,
+        Err(err) => {
+            log::warn!("failed to reconstruct stream gap after {from}: {err:#}");
+            Vec::new()
+        }
+    }
+}
+
+/// Synthesizes events between `from` and `to`, in a gap starting at `gap_start` after which
+/// `base` was the last `games` component.
+async fn synthesize(
+    config: &Config,
+    base: &RawValue,
+    gap_start: DateTime,
+    from: DateTime,
+    to: DateTime,
+) -> Result<Vec<Version<StreamEvent>>> {
+    let mut games: Map<String, Value> = serde_json::from_str(base.get())?;
+    let base_day = day_of(games.get("sim"));
+    // the latest state of each game, starting with the one in `base`
+    let mut states = HashMap::new();
+    let today = schedule_ids(&games, "schedule", &mut states);
+    let tomorrow = schedule_ids(&games, "tomorrowSchedule", &mut states);
+    let ids = today.iter().chain(&tomorrow).cloned().collect::<Vec<_>>();
+
+    let mut day = base_day;
+    let mut events = Vec::new();
+    for (time, changes) in fetch_changes(config, &ids, gap_start, from, to).await? {
+        let mut value = StreamValue {
+            games: None,
+            leagues: None,
+            temporal: None,
+            fights: None,
+        };
+        let mut games_changed = false;
+        for change in changes {
+            match change {
+                Change::Game(id, data) => {
+                    states.insert(id, serde_json::from_str(data.get())?);
+                    games_changed = true;
+                }
+                Change::Sim(data) => {
+                    let sim: Value = serde_json::from_str(data.get())?;
+                    day = day_of(Some(&sim)).or(day);
+                    games.insert("sim".into(), sim);
+                    games_changed = true;
+                }
+                Change::Temporal(data) => value.temporal = Some(Arc::from(data)),
+            }
+        }
+
+        if games_changed {
+            // after the day rolls over, tomorrow's games are today's
+            let (schedule, tomorrow_schedule): (&[String], &[String]) =
+                if day.is_some() && day > base_day {
+                    (&tomorrow, &[])
+                } else {
+                    (&today, &tomorrow)
+                };
+            for (key, ids) in [
+                ("schedule", schedule),
+                ("tomorrowSchedule", tomorrow_schedule),
+            ] {
+                if let Some(Value::Array(games)) = games.get_mut(key) {
+                    *games = ids
+                        .iter()
+                        .filter_map(|id| states.get(id).cloned())
+                        .collect();
+                }
+            }
+            value.games = Some(Arc::from(to_raw_value(&games)?));
+        }
+
+        events.push(Version {
+            valid_from: time,
+            valid_to: None,
+            entity_id: String::new(),
+            data: StreamEvent::synthesized(value),
+        });
+    }
+    Ok(events)
+}
+
+fn day_of(sim: Option<&Value>) -> Option<i64> {
+    sim?.get("day")?.as_i64()
+}
+
+/// Returns the IDs of the games in one of `games`' schedules, adding them to `states`.
+fn schedule_ids(
+    games: &Map<String, Value>,
+    key: &str,
+    states: &mut HashMap<String, Value>,
+) -> Vec<String> {
+    let schedule = games.get(key).and_then(Value::as_array);
+    schedule
+        .into_iter()
+        .flatten()
+        .filter_map(|game| {
+            let id = game.get("id").or_else(|| game.get("_id"))?.as_str()?;
+            states.insert(id.to_owned(), game.clone());
+            Some(id.to_owned())
+        })
+        .collect()
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GameUpdate {
+    game_id: String,
+    timestamp: DateTime,
+    data: Box<RawValue>,
+}
+
+enum Change {
+    Game(String, Box<RawValue>),
+    Sim(Box<RawValue>),
+    Temporal(Box<RawValue>),
+}
+
+/// Fetches the changes to the games in `ids`, `Sim` and `Temporal` between `from` and `to`, by
+/// time.
+async fn fetch_changes(
+    config: &Config,
+    ids: &[String],
+    gap_start: DateTime,
+    from: DateTime,
+    to: DateTime,
+) -> Result<BTreeMap<DateTime, Vec<Change>>> {
+    let updates = async {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        RequestBuilder::v1("games/updates")
+            .game(ids.join(","))
+            .after(from)
+            .before(to)
+            .order(Order::Asc)
+            .count(1000)
+            .paged_json(config)
+            .try_collect()
+            .await
+    };
+    let versions = |ty| {
+        RequestBuilder::v2("versions")
+            .ty(ty)
+            .after(from)
+            .before(to)
+            .order(Order::Asc)
+            .paged_json(config)
+            .try_collect()
+    };
+    let (updates, sims, temporals): (Vec<GameUpdate>, Vec<Version<_>>, Vec<Version<_>>) =
+        try_join!(updates, versions("Sim"), versions("Temporal"))?;
+
+    let mut changes: BTreeMap<DateTime, Vec<Change>> = BTreeMap::new();
+    // If we're starting partway into the gap, the last collected event is out of date by then, so
+    // start with everything's state at that point.
+    if from > gap_start {
+        let (games, sim, temporal) = try_join!(
+            try_join_all(ids.iter().map(|id| config.fetch_game(id.clone(), from))),
+            config.fetch::<Box<RawValue>>("Sim", None, from),
+            config.fetch::<Box<RawValue>>("Temporal", None, from),
+        )?;
+        changes.insert(
+            from,
+            ids.iter()
+                .zip(games)
+                .filter_map(|(id, game)| Some(Change::Game(id.clone(), game?)))
+                .chain(sim.map(Change::Sim))
+                .chain(temporal.map(Change::Temporal))
+                .collect(),
+        );
+    }
+    for update in updates {
+        (changes.entry(update.timestamp).or_default())
+            .push(Change::Game(update.game_id, update.data));
+    }
+    for version in sims {
+        (changes.entry(version.valid_from).or_default()).push(Change::Sim(version.data));
+    }
+    for version in temporals {
+        (changes.entry(version.valid_from).or_default()).push(Change::Temporal(version.data));
+    }
+    Ok(changes)
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn reconstruct_gap() {
+    use crate::source::fixture::Fixture;
+    use crate::stream::StreamValue;
+    use crate::time::datetime;
+    use serde_json::json;
+    use serde_json::value::RawValue;
+
+    let event = |time: &str, games: Option<&str>| Version {
+        valid_from: time.parse().unwrap(),
+        valid_to: None,
+        entity_id: String::new(),
+        data: StreamEvent::new(StreamValue {
+            games: games.map(|s| Arc::from(RawValue::from_string(s.into()).unwrap())),
+            leagues: None,
+            temporal: None,
+            fights: None,
+        }),
+    };
+
+    let mut config = Config::default();
+    // one update per page
+    config.source = Box::new(Fixture::new(|query| {
+        match (query.route, query.page.as_deref()) {
+            ("games/updates", None) => json!({ "nextPage": "1", "data": [
+            { "gameId": "g", "timestamp": "2021-03-01T00:00:30Z", "data": { "id": "g", "inning": 1 } },
+        ] }),
+            ("games/updates", Some(_)) => json!({ "data": [
+            { "gameId": "g", "timestamp": "2021-03-01T00:01:10Z", "data": { "id": "g", "inning": 2 } },
+        ] }),
+            _ => json!({ "items": [] }),
+        }
+    }));
+    let mut events = vec![
+        event(
+            "2021-03-01T00:00:00Z",
+            Some(r#"{"sim":{"day":1},"schedule":[{"id":"g","inning":0}],"tomorrowSchedule":[]}"#),
+        ),
+        event("2021-03-01T00:10:00Z", None),
+    ];
+
+    fill(&config, &mut events, datetime!(2021-03-01 00:00:15 UTC)).await;
+    let synthesized = events
+        .iter()
+        .filter(|v| v.data.synthesized)
+        .map(|v| (v.valid_from, v.data.value.games.as_ref().unwrap().get()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        synthesized,
+        [
+            (
+                datetime!(2021-03-01 00:00:30 UTC),
+                r#"{"schedule":[{"id":"g","inning":1}],"sim":{"day":1},"tomorrowSchedule":[]}"#
+            ),
+            (
+                datetime!(2021-03-01 00:01:10 UTC),
+                r#"{"schedule":[{"id":"g","inning":2}],"sim":{"day":1},"tomorrowSchedule":[]}"#
+            ),
+        ]
+    );
+    assert_eq!(events.len(), 4);
+}
@@ -5,6 +5,7 @@ use crate::time::{datetime, DateTime};
 use anyhow::Result;
 use serde::Serialize;
 use serde_json::value::RawValue;
+use std::sync::Arc;
 use tokio::try_join;
 
 const LEAGUES_START: DateTime = datetime!(2020-09-03 21:40:38.266 UTC);
@@ -13,7 +14,7 @@ const TIEBREAKERS_START: DateTime = datetime!(2020-09-10 17:51:30.577 UTC);
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
 pub(crate) enum Leagues {
-    Value(Box<RawValue>),
+    Value(Arc<RawValue>),
     Constructed {
         leagues: Vec<Box<RawValue>>,
         stadiums: Vec<Box<RawValue>>,
@@ -1,33 +1,78 @@
+mod broadcast;
+mod cache;
+mod disk;
 mod games;
+mod gaps;
 mod leagues;
+pub(crate) mod patch;
 mod postseason;
 
 use crate::chronicler::{Order, RequestBuilder, Version, Versions};
 use crate::config::Config;
-use crate::offset::{Offset, OffsetTime};
+use crate::metrics::METRICS;
+use crate::offset::Clock;
+use crate::provenance::{Provenance, Source, Transform};
 use crate::stream::{games::Games, leagues::Leagues};
 use crate::time::{DateTime, Duration};
 use anyhow::Result;
 use itertools::Itertools;
 use rand::{thread_rng, Rng};
-use rocket::futures::Stream;
+use rocket::futures::{Stream, StreamExt};
 use rocket::response::stream::stream;
 use rocket::Shutdown;
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, value::RawValue};
-use std::collections::{hash_map::DefaultHasher, BTreeMap, HashSet};
+use serde_json::json;
+use serde_json::value::{to_raw_value, RawValue};
+use std::collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::sync::Arc;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration as StdDuration;
-use tokio::{select, time::sleep, try_join};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::OnceCell;
+use tokio::time::sleep;
+use tokio::{select, try_join};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct StreamEvent {
     pub(crate) value: StreamValue,
+    /// Whether this event was reconstructed from other data during a gap in Chronicler's `Stream`
+    /// versions, rather than collected (see `gaps`).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) synthesized: bool,
+    /// This event's JSON, serialized the first time it's sent and shared by every later stream.
+    #[serde(skip)]
+    json: OnceCell<Arc<str>>,
 }
 
 impl StreamEvent {
+    pub(crate) fn new(value: StreamValue) -> StreamEvent {
+        StreamEvent {
+            value,
+            synthesized: false,
+            json: OnceCell::new(),
+        }
+    }
+
+    pub(crate) fn synthesized(value: StreamValue) -> StreamEvent {
+        StreamEvent {
+            synthesized: true,
+            ..StreamEvent::new(value)
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Result<Arc<str>> {
+        if let Some(json) = self.json.get() {
+            return Ok(json.clone());
+        }
+        let json: Arc<str> = serde_json::to_string(self)?.into();
+        // if another stream got here first, both serialized the same thing
+        let _ = self.json.set(json.clone());
+        Ok(json)
+    }
+
     pub(crate) fn is_empty(&self) -> bool {
         self.value.games.is_none()
             && self.value.leagues.is_none()
@@ -39,17 +84,26 @@ impl StreamEvent {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) struct StreamValue {
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) games: Option<Box<RawValue>>,
+    pub(crate) games: Option<Arc<RawValue>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) leagues: Option<Box<RawValue>>,
+    pub(crate) leagues: Option<Arc<RawValue>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) temporal: Option<Box<RawValue>>,
+    pub(crate) temporal: Option<Arc<RawValue>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) fights: Option<Box<RawValue>>,
+    pub(crate) fights: Option<Arc<RawValue>>,
 }
 
+pub(crate) use broadcast::Broadcaster;
+pub(crate) use cache::StreamCache;
+pub(crate) use disk::DiskCache;
+pub(crate) use games::GameIndexFlight;
+
 pub(crate) type StreamCacheValue = (First, Vec<Arc<Version<StreamEvent>>>);
 
+/// Buckets currently being loaded by [`load_bucket`], so that concurrent cache misses for the same
+/// bucket wait for the first one instead of all building it.
+pub(crate) type StreamFlights = StdMutex<HashMap<DateTime, Arc<OnceCell<StreamCacheValue>>>>;
+
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
 lazy_static::lazy_static! {
@@ -63,31 +117,56 @@ fn check_inject() {
     assert!(!INJECT.is_empty());
 }
 
-async fn start_cold(config: &Config, cache_time: DateTime) -> Result<StreamCacheValue> {
+/// Fetches the stream versions around a cache bucket.
+async fn fetch_events(config: &Config, cache_time: DateTime) -> Result<Vec<Version<StreamEvent>>> {
     // A given `StreamEvent` version does not necessarily have all the top-level fields present,
     // but the frontend needs all fields present in the first event to be fully functional. We
-    // fetch the previous 25 events, so that we can construct a "first" event to send immediately.
+    // fetch the previous events (25 by default), so that we can construct a "first" event to send
+    // immediately.
     //
-    // We fetch the next 35 events. There is no need to fetch further than a minute (plus the 15
-    // second cache bucket window) out, because the frontend (in nearly all season) is hardcoded to
-    // close and reopen the stream every 40 seconds... but in particularly contrived cases we might
-    // get updates more often than the usual 4 second interval.
+    // We fetch the next events (35 by default). There is no need to fetch further than a minute
+    // (plus the cache bucket window) out, because the frontend (in nearly all season) is hardcoded
+    // to close and reopen the stream every 40 seconds... but in particularly contrived cases we
+    // might get updates more often than the usual 4 second interval.
+    let lookback = RequestBuilder::v2("versions")
+        .ty("Stream")
+        .before(cache_time)
+        .count(config.stream_lookback_count)
+        .order(Order::Desc);
     let (past, future): (Versions<StreamEvent>, Versions<StreamEvent>) = try_join!(
-        RequestBuilder::v2("versions")
-            .ty("Stream")
-            .before(cache_time)
-            .count(25)
-            .order(Order::Desc)
-            .json(config),
+        lookback.clone().json(config),
         RequestBuilder::v2("versions")
             .ty("Stream")
             .after(cache_time)
-            .count(35)
+            .count(config.stream_lookahead_count)
             .order(Order::Asc)
             .json(config),
     )?;
     let mut events = past.items;
+
+    // If a component is missing from those, keep paging back for it until the lookback horizon.
+    // Rebuilding `games` and `leagues` from entities is much slower, and fights can't be rebuilt at
+    // all. Fights are only in the stream during a boss fight, so most of the time there's nothing
+    // to find; they have a shorter horizon of their own.
+    let horizon = |secs: u64| cache_time - Duration::seconds(secs.try_into().unwrap_or(i64::MAX));
+    let fights_horizon = horizon(config.stream_fights_horizon_secs);
+    let horizon = horizon(config.stream_lookback_horizon_secs);
+    let mut next_page = past.next_page;
+    while let Some(page) = next_page.take() {
+        let oldest = events.last().map_or(cache_time, |v| v.valid_from);
+        if oldest <= horizon || has_every_component(&events, oldest > fights_horizon) {
+            break;
+        }
+        let older = lookback.clone().page(page).json(config).await?;
+        next_page = older.next_page;
+        events.extend(older.items);
+    }
     events.extend(future.items);
+    Ok(events)
+}
+
+async fn start_cold(config: &Config, cache_time: DateTime) -> Result<StreamCacheValue> {
+    let mut events = fetch_events(config, cache_time).await?;
 
     // Inject events into the stream if defined in data/inject.json. Note that injected events are
     // also checked when rebuilding the temporal object if missing
@@ -95,145 +174,351 @@ async fn start_cold(config: &Config, cache_time: DateTime) -> Result<StreamCache
         max += Duration::minutes(1);
         events.extend(INJECT.range(min..=max).map(|(k, v)| Version {
             valid_from: *k,
+            valid_to: None,
             entity_id: String::new(),
-            data: StreamEvent { value: v.clone() },
+            data: StreamEvent::new(v.clone()),
         }));
     }
 
     events.sort_by_key(|v| v.valid_from);
+    gaps::fill(config, &mut events, cache_time).await;
 
-    // Multiple data sources perceive events at different times, even with accurate clocks, due to
-    // the nature of blaseball.com's event stream. We can mostly mitigate this effect by deduping
-    // the individual components of the stream.
-    {
-        let mut seen = HashSet::new();
-        for event in &mut events {
-            macro_rules! dedup {
-                ($x:ident) => {
-                    event.data.value.$x = if let Some(value) = event.data.value.$x.take() {
-                        // Sometimes we perceived empty top-level objects (other than fights)?
-                        // This most notably happened after Tillman swapped with Jaylen in Season
-                        // 10 (at 2020-10-16T20:06:42.130679Z). These crash frontend, so yank them
-                        // out of the stream with the worst hack you've ever seen
-                        if stringify!($x) != "fights" && value.get() == "{}" {
-                            None
-                        }
-                        // For being messages, we can sometimes end up in a situation where we
-                        // perceive the message being set while epsilon is false, then epsilon is
-                        // set true, then set false again to hide. The last message where epsilon
-                        // is false will be deduped. As a workaround, don't dedupe any message
-                        // where epsilon is false.
-                        else if stringify!($x) == "temporal"
-                            && read_epsilon(value.get()) == Some(false)
-                        {
-                            Some(value)
-                        } else {
-                            let mut hasher = DefaultHasher::new();
-                            value.get().hash(&mut hasher);
-                            let key = (stringify!($x), hasher.finish());
-                            if seen.contains(&key) {
-                                None
-                            } else {
-                                seen.insert(key);
-                                Some(value)
-                            }
-                        }
-                    } else {
-                        None
-                    }
-                };
-            }
-
-            dedup!(games);
-            dedup!(leagues);
-            dedup!(temporal);
-            dedup!(fights);
-        }
+    let mut dedup = Dedup::default();
+    for event in &mut events {
+        dedup.apply(event);
+    }
+    // Neighbouring buckets fetch mostly the same events; share their components in memory.
+    for event in &mut events {
+        cache::intern_value(&mut event.data.value);
     }
 
     let (mut past, future): (Vec<Version<StreamEvent>>, Vec<Version<StreamEvent>>) = events
         .into_iter()
         .filter(|event| !event.data.is_empty())
         .partition(|event| event.valid_from <= cache_time);
+    // Note where each component comes from before it's taken out of `past`.
+    let mut sources = Box::new(Sources::default());
+    for version in &past {
+        sources.update(version);
+    }
+    sources.temporal = None;
+    // Constructed components are serialized here once, rather than for every stream they're sent
+    // on.
     let first = First {
-        games: Arc::new(Games::first(config, &mut past, cache_time).await?),
-        leagues: Arc::new(Leagues::first(config, &mut past, cache_time).await?),
-        temporal: Arc::from(first_temporal(config, &mut past, cache_time).await?),
-        fights: first_fights(&mut past).map(Arc::from),
+        games: match Games::first(config, &mut past, cache_time).await? {
+            Games::Value(value) => value,
+            games @ Games::Constructed { .. } => {
+                sources.games = Some(Provenance::new(Source::Constructed, None));
+                cache::intern(Arc::from(to_raw_value(&games)?))
+            }
+        },
+        leagues: match Leagues::first(config, &mut past, cache_time).await? {
+            Leagues::Value(value) => value,
+            leagues @ Leagues::Constructed { .. } => {
+                sources.leagues =
+                    Some(Provenance::new(Source::Constructed, None).with(Transform::FixId));
+                cache::intern(Arc::from(to_raw_value(&leagues)?))
+            }
+        },
+        temporal: {
+            let (temporal, source) = first_temporal(config, &mut past, cache_time).await?;
+            sources.temporal = Some(source);
+            temporal
+        },
+        fights: first_fights(&mut past),
+        sources,
     };
 
-    let value = (first, future.into_iter().map(Arc::new).collect());
-    if let Some(cache) = &config.stream_cache {
-        let mut guard = cache.lock().await;
-        if !guard.contains(&cache_time) {
-            guard.put(cache_time, value.clone());
+    Ok((first, future.into_iter().map(Arc::new).collect()))
+}
+
+/// Loads a bucket from the disk cache or builds it with [`start_cold`], then adds it to the
+/// in-memory cache.
+async fn load_bucket(config: &Config, cache_time: DateTime) -> Result<StreamCacheValue> {
+    let disk = config.stream_disk_cache.as_ref();
+    let value = match disk {
+        Some(disk) => disk.get(cache_time).await,
+        None => None,
+    };
+    let value = if let Some(value) = value {
+        value
+    } else {
+        let value = start_cold(config, cache_time).await?;
+        if let Some(disk) = disk {
+            if let Err(err) = disk.put(cache_time, &value).await {
+                log::warn!("failed to write stream bucket {cache_time} to disk: {err:#}");
+            }
         }
+        value
+    };
+
+    if let Some(cache) = &config.stream_cache {
+        cache.lock().await.put(cache_time, value.clone());
     }
     Ok(value)
 }
 
-pub(crate) async fn start(
-    config: &Config,
-    time: OffsetTime,
-    offset: Offset,
-    mut shutdown: Shutdown,
-) -> Result<impl Stream<Item = Item> + Send + Sync> {
-    let cache_time = time.0.trunc(Duration::seconds(15))?;
+/// How long an accelerated stream keeps loading later events, and how long a paused stream idles,
+/// in wall-clock time. The frontend reopens the stream every 40 seconds.
+const STREAM_LENGTH: StdDuration = StdDuration::from_secs(45);
+
+async fn cache_bucket(config: &Config, cache_time: DateTime) -> Result<StreamCacheValue> {
     let cached = if let Some(cache) = &config.stream_cache {
-        cache.lock().await.get(&cache_time).cloned()
+        let cached = cache.lock().await.get(&cache_time).cloned();
+        if cached.is_some() {
+            METRICS.stream_cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            METRICS.stream_cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        cached
     } else {
         None
     };
 
-    let (first_orig, events) = match cached {
-        Some(x) => x,
-        None => start_cold(config, cache_time).await?,
-    };
+    if let Some(x) = cached {
+        return Ok(x);
+    }
 
-    let (past, future): (Vec<_>, Vec<_>) = events
-        .into_iter()
-        .partition(|event| event.valid_from <= time.0);
-    let first = First {
-        games: past
-            .iter()
-            .rev()
-            .find_map(|v| v.data.value.games.as_ref().cloned())
-            .map_or_else(|| first_orig.games.clone(), |v| Arc::new(Games::Value(v))),
-        leagues: past
-            .iter()
-            .rev()
-            .find_map(|v| v.data.value.leagues.as_ref().cloned())
-            .map_or_else(
-                || first_orig.leagues.clone(),
-                |v| Arc::new(Leagues::Value(v)),
-            ),
-        temporal: past
-            .iter()
-            .rev()
-            .find_map(|v| v.data.value.temporal.as_ref().cloned())
-            .map_or_else(|| first_orig.temporal.clone(), Arc::from),
-        fights: past
-            .iter()
-            .rev()
-            .find_map(|v| v.data.value.fights.as_ref().cloned())
-            .map(Arc::from)
-            .or_else(|| first_orig.fights.clone()),
+    let flight = config
+        .stream_flights
+        .lock()
+        .unwrap()
+        .entry(cache_time)
+        .or_default()
+        .clone();
+    // if the first caller fails, the next one waiting takes over
+    let value = flight
+        .get_or_try_init(|| load_bucket(config, cache_time))
+        .await
+        .map(|(first, events)| (first.clone(), events.clone()));
+    let mut flights = config.stream_flights.lock().unwrap();
+    if flights
+        .get(&cache_time)
+        .map_or(false, |v| Arc::ptr_eq(v, &flight))
+    {
+        flights.remove(&cache_time);
+    }
+    value
+}
+
+/// How far behind the current perceived time a stream can be resumed from.
+const RESUME_WINDOW: Duration = Duration::minutes(1);
+
+/// Starts a stream at the current perceived time.
+///
+/// If `resume` is the time of the last item a client received, and it's recent enough, the stream
+/// starts with the updates the client missed since then instead of an `Item::Start`.
+pub(crate) async fn start(
+    config: &Arc<Config>,
+    clock: Clock,
+    resume: Option<DateTime>,
+    mut shutdown: Shutdown,
+) -> Result<impl Stream<Item = Item> + Send> {
+    let now = clock.now();
+    let resume = resume.filter(|since| *since <= now && now - *since <= RESUME_WINDOW);
+    // the missed updates are all in the bucket the client left off in
+    let cache_time = resume.unwrap_or(now).trunc(config.stream_bucket())?;
+    let (first_orig, events) = cache_bucket(config, cache_time).await?;
+    // Subscribe before deciding what goes in `Item::Start`, so that every update is either part of
+    // it or received from the scheduler.
+    let rx = if clock.playback.paused {
+        None
+    } else {
+        Some(
+            config
+                .broadcaster
+                .subscribe(config, cache_time, clock, &events, shutdown.clone()),
+        )
     };
+    let time = clock.now();
+    let past = events
+        .into_iter()
+        .take_while(|event| event.valid_from <= time)
+        .collect::<Vec<_>>();
+    let mut first = first_orig;
+    for version in &past {
+        first.update(version);
+    }
 
     Ok(stream! {
-        yield Item::Start(first);
-        for version in future {
-            let duration = StdDuration::try_from(version.valid_from - (DateTime::now() - offset.0))
-                .unwrap_or_else(|_| StdDuration::from_nanos(0));
+        if let Some(since) = resume {
+            for version in past.into_iter().filter(|version| version.valid_from > since) {
+                yield Item::Update(version);
+            }
+        } else {
+            yield Item::Start(time, first);
+        }
+        // while time is paused, idle until the client reopens the stream
+        if let Some(mut rx) = rx {
+            loop {
+                select! {
+                    item = rx.recv() => match item {
+                        Ok(Item::Update(version)) if version.valid_from > time => {
+                            yield Item::Update(version);
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(n)) => log::warn!("stream fell behind by {n} updates"),
+                        Err(RecvError::Closed) => break,
+                    },
+                    () = &mut shutdown => break,
+                }
+            }
+        } else {
             select! {
-                _ = sleep(duration) => {},
-                _ = &mut shutdown => {},
+                _ = sleep(STREAM_LENGTH) => {},
+                () = &mut shutdown => {},
+            }
+        }
+    })
+}
+
+/// Replays the stream between `from` and `to` as fast as it can be fetched: an `Item::Start` as a
+/// live viewer would see it at `from`, then every update after that, up to and including `to`.
+///
+/// The updates come from one paged query for the whole window, with injected and reconstructed
+/// events added and components deduped as they are for a bucket. If a page fails to load, the
+/// stream ends early.
+pub(crate) async fn export(
+    config: &Arc<Config>,
+    from: DateTime,
+    to: DateTime,
+) -> Result<impl Stream<Item = Item> + Send> {
+    let (mut first, events) = cache_bucket(config, from.trunc(config.stream_bucket())?).await?;
+    for version in events.iter().take_while(|event| event.valid_from <= from) {
+        first.update(version);
+    }
+    let mut dedup = Dedup::default();
+    dedup.insert_first(&first);
+    let mut games = first.games.clone();
+
+    let config = config.clone();
+    Ok(stream! {
+        yield Item::Start(from, first);
+
+        // `before` is exclusive
+        let mut versions = Box::pin(
+            RequestBuilder::v2("versions")
+                .ty("Stream")
+                .after(from)
+                .before(to + Duration::SECOND)
+                .order(Order::Asc)
+                .paged_json(&config),
+        );
+        let mut last = from;
+        loop {
+            let next: Option<Version<StreamEvent>> = match versions.next().await {
+                Some(Ok(version)) if version.valid_from <= to => Some(version),
+                Some(Err(err)) => {
+                    log::warn!("stream export stopped after {last}: {err:#}");
+                    break;
+                }
+                Some(Ok(_)) | None => None,
+            };
+            let until = next.as_ref().map_or(to, |version| version.valid_from);
+
+            // everything between the last version and this one, then this one
+            let mut events = INJECT
+                .range((Bound::Excluded(last), Bound::Included(until)))
+                .map(|(time, value)| Version {
+                    valid_from: *time,
+                    valid_to: None,
+                    entity_id: String::new(),
+                    data: StreamEvent::new(value.clone()),
+                })
+                .collect::<Vec<_>>();
+            if next.is_some() {
+                events.extend(gaps::fill_gap(&config, Some(&games), last, until, from, to).await);
             }
-            yield Item::Update(version);
+            events.sort_by_key(|v| v.valid_from);
+            events.extend(next);
+
+            for mut event in events {
+                if let Some(value) = &event.data.value.games {
+                    games = value.clone();
+                }
+                dedup.apply(&mut event);
+                if !event.data.is_empty() {
+                    yield Item::Update(Arc::new(event));
+                }
+            }
+            if until >= to {
+                break;
+            }
+            last = until;
         }
     })
 }
 
+/// Drops the components of events that were already perceived.
+///
+/// Multiple data sources perceive events at different times, even with accurate clocks, due to
+/// the nature of blaseball.com's event stream. We can mostly mitigate this effect by deduping
+/// the individual components of the stream.
+#[derive(Default)]
+struct Dedup {
+    seen: HashSet<(&'static str, u64)>,
+}
+
+impl Dedup {
+    fn key(name: &'static str, value: &RawValue) -> (&'static str, u64) {
+        let mut hasher = DefaultHasher::new();
+        value.get().hash(&mut hasher);
+        (name, hasher.finish())
+    }
+
+    /// Marks each component of `first` as perceived.
+    fn insert_first(&mut self, first: &First) {
+        for (name, value) in [
+            ("games", Some(&first.games)),
+            ("leagues", Some(&first.leagues)),
+            ("temporal", Some(&first.temporal)),
+            ("fights", first.fights.as_ref()),
+        ] {
+            if let Some(value) = value {
+                self.seen.insert(Dedup::key(name, value));
+            }
+        }
+    }
+
+    fn apply(&mut self, event: &mut Version<StreamEvent>) {
+        macro_rules! dedup {
+            ($x:ident) => {
+                event.data.value.$x = if let Some(value) = event.data.value.$x.take() {
+                    // Sometimes we perceived empty top-level objects (other than fights)?
+                    // This most notably happened after Tillman swapped with Jaylen in Season
+                    // 10 (at 2020-10-16T20:06:42.130679Z). These crash frontend, so yank them
+                    // out of the stream with the worst hack you've ever seen
+                    if stringify!($x) != "fights" && value.get() == "{}" {
+                        None
+                    }
+                    // For being messages, we can sometimes end up in a situation where we
+                    // perceive the message being set while epsilon is false, then epsilon is
+                    // set true, then set false again to hide. The last message where epsilon
+                    // is false will be deduped. As a workaround, don't dedupe any message
+                    // where epsilon is false.
+                    else if stringify!($x) == "temporal"
+                        && read_epsilon(value.get()) == Some(false)
+                    {
+                        Some(value)
+                    } else {
+                        // `insert` is false if it was already there
+                        self.seen
+                            .insert(Dedup::key(stringify!($x), &value))
+                            .then(|| value)
+                    }
+                } else {
+                    None
+                }
+            };
+        }
+
+        dedup!(games);
+        dedup!(leagues);
+        dedup!(temporal);
+        dedup!(fights);
+    }
+}
+
 fn read_epsilon(value: &str) -> Option<bool> {
     #[derive(Deserialize)]
     struct Temporal {
@@ -250,18 +535,51 @@ fn read_epsilon(value: &str) -> Option<bool> {
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
+#[derive(Clone)]
 pub(crate) enum Item {
-    Start(First),
+    /// The state of every component at a perceived time.
+    Start(DateTime, First),
     Update(Arc<Version<StreamEvent>>),
 }
 
+impl Item {
+    /// The perceived time this item brings a client up to.
+    pub(crate) fn time(&self) -> DateTime {
+        match self {
+            Item::Start(time, _) => *time,
+            Item::Update(version) => version.valid_from,
+        }
+    }
+
+    /// Where each component in this item came from, for `X-Before-Debug`.
+    pub(crate) fn sources(&self) -> Sources {
+        match self {
+            Item::Start(_, first) => (*first.sources).clone(),
+            Item::Update(version) => {
+                let mut sources = Sources::default();
+                sources.update(version);
+                sources
+            }
+        }
+    }
+
+    /// Returns the item's JSON payload, as sent over SSE and the WebSocket transport. Each update is only
+    /// serialized once, however many streams it's sent on.
+    pub(crate) fn to_json(&self) -> serde_json::Result<Arc<str>> {
+        match self {
+            Item::Start(..) => serde_json::to_string(self).map(Arc::from),
+            Item::Update(version) => version.data.to_json(),
+        }
+    }
+}
+
 impl Serialize for Item {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
         match self {
-            Item::Start(x) => {
+            Item::Start(_, x) => {
                 let mut wrapper = serializer.serialize_struct("StreamEvent", 1)?;
                 wrapper.serialize_field("value", x)?;
                 wrapper.end()
@@ -273,26 +591,91 @@ impl Serialize for Item {
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) struct First {
-    pub(crate) games: Arc<Games>,
-    pub(crate) leagues: Arc<Leagues>,
+    pub(crate) games: Arc<RawValue>,
+    pub(crate) leagues: Arc<RawValue>,
     pub(crate) temporal: Arc<RawValue>,
     pub(crate) fights: Option<Arc<RawValue>>,
+    #[serde(skip)]
+    pub(crate) sources: Box<Sources>,
+}
+
+impl First {
+    /// Replaces each component present in `version`.
+    fn update(&mut self, version: &Version<StreamEvent>) {
+        let value = &version.data.value;
+        if let Some(games) = &value.games {
+            self.games = games.clone();
+        }
+        if let Some(leagues) = &value.leagues {
+            self.leagues = leagues.clone();
+        }
+        if let Some(temporal) = &value.temporal {
+            self.temporal = temporal.clone();
+        }
+        if let Some(fights) = &value.fights {
+            self.fights = Some(fights.clone());
+        }
+        self.sources.update(version);
+    }
+}
+
+/// The provenance of each stream component, sent as the `_before` key of stream events when
+/// requested with `X-Before-Debug`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub(crate) struct Sources {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) games: Option<Provenance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) leagues: Option<Provenance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) temporal: Option<Provenance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) fights: Option<Provenance>,
+}
+
+impl Sources {
+    /// Records `version` as the source of each component present in it.
+    fn update(&mut self, version: &Version<StreamEvent>) {
+        let provenance = event_provenance(version);
+        let value = &version.data.value;
+        for (has, slot) in [
+            (value.games.is_some(), &mut self.games),
+            (value.leagues.is_some(), &mut self.leagues),
+            (value.temporal.is_some(), &mut self.temporal),
+            (value.fights.is_some(), &mut self.fights),
+        ] {
+            if has {
+                *slot = Some(provenance.clone());
+            }
+        }
+    }
+}
+
+fn event_provenance(version: &Version<StreamEvent>) -> Provenance {
+    let source = if version.data.synthesized {
+        Source::Synthesized
+    } else if version.entity_id.is_empty() {
+        // injected events don't have an entity
+        Source::Inject
+    } else {
+        Source::Chronicler
+    };
+    Provenance::new(source, Some(version.valid_from))
 }
 
 async fn first_temporal(
     config: &Config,
     past: &mut [Version<StreamEvent>],
     time: DateTime,
-) -> Result<Box<RawValue>> {
+) -> Result<(Arc<RawValue>, Provenance)> {
     Ok(
-        if let Some(version) = past
-            .iter_mut()
-            .rev()
-            .find_map(|v| v.data.value.temporal.take())
-        {
-            version
+        if let Some(found) = past.iter_mut().rev().find_map(|v| {
+            let provenance = event_provenance(v);
+            Some((v.data.value.temporal.take()?, provenance))
+        }) {
+            found
         } else if let Some(version) = RequestBuilder::v2("entities")
             .ty("Temporal")
             .at(time)
@@ -302,24 +685,12 @@ async fn first_temporal(
             .into_iter()
             .next()
         {
-            match INJECT
-                .range(version.valid_from..=time)
-                .filter_map(|(_, v)| v.temporal.clone())
-                .rev()
-                .next()
-            {
-                Some(inject) => inject,
-                None => version.data,
-            }
-        } else if let Some(inject) = INJECT
-            .range(..=time)
-            .filter_map(|(_, v)| v.temporal.clone())
-            .rev()
-            .next()
-        {
+            let provenance = Provenance::chronicler(&version);
+            latest_inject_temporal(version.valid_from..=time).unwrap_or((version.data, provenance))
+        } else if let Some(inject) = latest_inject_temporal(..=time) {
             inject
         } else {
-            serde_json::from_value(json!({
+            let temporal = serde_json::from_value(json!({
                 "doc": {
                     "id": "whatistime",
                     "alpha": thread_rng().gen_range(1..15),
@@ -329,15 +700,228 @@ async fn first_temporal(
                     "epsilon": false,
                     "zeta": "",
                 }
-            }))?
+            }))?;
+            (temporal, Provenance::new(Source::Fallback, None))
         },
     )
 }
 
-fn first_fights(past: &mut [Version<StreamEvent>]) -> Option<Box<RawValue>> {
-    // if there weren't any fights defined in the last 25 events it probably means there's not a
-    // boss fight right now
+fn latest_inject_temporal(
+    range: impl RangeBounds<DateTime>,
+) -> Option<(Arc<RawValue>, Provenance)> {
+    INJECT
+        .range(range)
+        .filter_map(|(time, v)| Some((v.temporal.clone()?, *time)))
+        .rev()
+        .next()
+        .map(|(temporal, time)| (temporal, Provenance::new(Source::Inject, Some(time))))
+}
+
+/// Whether `events` has every component, counting `fights` only if `fights` is set.
+fn has_every_component(events: &[Version<StreamEvent>], fights: bool) -> bool {
+    let has = |f: fn(&StreamValue) -> bool| events.iter().any(|v| f(&v.data.value));
+    has(|v| v.games.is_some())
+        && has(|v| v.leagues.is_some())
+        && has(|v| v.temporal.is_some())
+        && (!fights || has(|v| v.fights.is_some()))
+}
+
+fn first_fights(past: &mut [Version<StreamEvent>]) -> Option<Arc<RawValue>> {
+    // if there weren't any fights defined back to the fights horizon it probably means there's not
+    // a boss fight right now
     past.iter_mut()
         .rev()
         .find_map(|v| v.data.value.fights.take())
 }
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn single_flight_cold_start() {
+    use crate::source::fixture::Fixture;
+    use crate::time::datetime;
+    use serde_json::json;
+
+    let fixture = Fixture::new(|_| json!({ "items": [] })).delay(StdDuration::from_millis(10));
+    let queries = fixture.queries();
+    let mut config = Config::default();
+    config.source = Box::new(fixture);
+
+    let time = datetime!(2021-03-01 00:00:00 UTC);
+    let (a, b) = tokio::join!(cache_bucket(&config, time), cache_bucket(&config, time));
+    a.unwrap();
+    b.unwrap();
+    // one query for past events and one for future events
+    let queries = queries.lock().unwrap();
+    assert_eq!(queries.iter().filter(|q| q.ty == Some("Stream")).count(), 2);
+    assert!(config.stream_flights.lock().unwrap().is_empty());
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn lookback_for_fights() {
+    use crate::source::fixture::{stream_versions, versions_page, Fixture, STREAM_START};
+    use serde_json::json;
+
+    // the only fight is 50 versions before the bucket, twice the lookback count
+    let mut versions = stream_versions();
+    versions[10].1["data"]["value"]["fights"] = json!({ "boss": true });
+    let fixture = Fixture::new(move |query| {
+        if query.ty != Some("Stream") {
+            return json!({ "items": [] });
+        }
+        versions_page(query, versions.clone())
+    });
+    let queries = fixture.queries();
+    let mut config = Config::default();
+    config.source = Box::new(fixture);
+
+    let (first, _) = cache_bucket(&config, STREAM_START + Duration::minutes(5))
+        .await
+        .unwrap();
+    assert_eq!(first.fights.unwrap().get(), r#"{"boss":true}"#);
+    // the lookahead, the lookback, and one more page back to the fight
+    let queries = queries.lock().unwrap();
+    assert_eq!(queries.iter().filter(|q| q.ty == Some("Stream")).count(), 3);
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn export_window() {
+    use crate::source::fixture::{Fixture, STREAM_START};
+    use rocket::futures::StreamExt;
+
+    let fixture = Fixture::stream();
+    let queries = fixture.queries();
+    let mut config = Config::default();
+    config.source = Box::new(fixture);
+    let config = Arc::new(config);
+
+    let at = |secs| STREAM_START + Duration::seconds(secs);
+    let items = export(&config, at(7), at(120))
+        .await
+        .unwrap()
+        .collect::<Vec<_>>()
+        .await;
+    match &items[0] {
+        Item::Start(_, first) => assert_eq!(first.games.get(), r#"{"n":1}"#),
+        Item::Update(_) => panic!("export didn't start with a snapshot"),
+    }
+    let times = items.iter().map(Item::time).collect::<Vec<_>>();
+    assert_eq!(
+        times,
+        std::iter::once(at(7))
+            .chain((10..=120).step_by(5).map(at))
+            .collect::<Vec<_>>()
+    );
+
+    // one bucket for the snapshot, then one query for the rest
+    let queries = queries.lock().unwrap();
+    let afters = (queries.iter())
+        .filter(|q| q.ty == Some("Stream"))
+        .map(|q| q.after)
+        .collect::<Vec<_>>();
+    assert_eq!(afters, [None, Some(at(0)), Some(at(7))]);
+}
+
+#[cfg(test)]
+fn test_clock(time: DateTime, rate: f64) -> Clock {
+    use crate::offset::{Offset, Playback};
+
+    let anchor = DateTime::now();
+    Clock {
+        offset: Offset(anchor - time),
+        playback: Playback {
+            rate,
+            anchor,
+            paused: false,
+        },
+    }
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn shared_serialized_updates() {
+    use crate::source::fixture::{self, Fixture, STREAM_START};
+    use rocket::futures::StreamExt;
+
+    let mut config = Config::default();
+    config.source = Box::new(Fixture::stream());
+    let config = Arc::new(config);
+    let shutdown = fixture::shutdown().await;
+    // an update every half second
+    let clock = test_clock(STREAM_START + Duration::seconds(12), 10.0);
+
+    let a = start(&config, clock, None, shutdown.clone()).await.unwrap();
+    let b = start(&config, clock, None, shutdown.clone()).await.unwrap();
+    let (a, b) = tokio::join!(a.take(3).collect::<Vec<_>>(), b.take(3).collect::<Vec<_>>());
+    assert!(matches!(a[0], Item::Start(..)));
+    for (a, b) in a.iter().zip(&b) {
+        assert_eq!(
+            a.to_json().unwrap().as_bytes(),
+            b.to_json().unwrap().as_bytes()
+        );
+        if let (Item::Update(_), Item::Update(_)) = (a, b) {
+            // serialized once, then shared
+            assert!(Arc::ptr_eq(&a.to_json().unwrap(), &b.to_json().unwrap()));
+        }
+    }
+    let times = b.iter().map(Item::time).skip(1).collect::<Vec<_>>();
+    assert_eq!(
+        times,
+        [
+            STREAM_START + Duration::seconds(15),
+            STREAM_START + Duration::seconds(20)
+        ]
+    );
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn resume_from_last_event() {
+    use crate::source::fixture::{self, Fixture, STREAM_START};
+    use rocket::futures::StreamExt;
+
+    let mut config = Config::default();
+    config.source = Box::new(Fixture::stream());
+    let config = Arc::new(config);
+    let shutdown = fixture::shutdown().await;
+    let at = |secs| STREAM_START + Duration::seconds(secs);
+    let mut clock = test_clock(at(40), 1.0);
+    // keep perceived time still while the streams are read
+    clock.playback.paused = true;
+    let items = |resume| {
+        let (config, shutdown) = (&config, shutdown.clone());
+        async move {
+            let stream = start(config, clock, resume, shutdown).await.unwrap();
+            // a paused stream idles once it's caught up
+            let stream = stream.take_until(sleep(StdDuration::from_millis(200)));
+            (stream.collect::<Vec<_>>().await)
+                .into_iter()
+                .map(|item| (matches!(item, Item::Start(..)), item.time()))
+                .collect::<Vec<_>>()
+        }
+    };
+
+    // within `RESUME_WINDOW`, only the updates the client missed are sent, even across buckets
+    assert_eq!(
+        items(Some(at(22))).await,
+        [
+            (false, at(25)),
+            (false, at(30)),
+            (false, at(35)),
+            (false, at(40))
+        ]
+    );
+    assert_eq!(items(Some(at(40))).await, []);
+
+    // otherwise, the stream starts over
+    let restart = [(true, clock.now())];
+    assert_eq!(
+        items(Some(at(40) - RESUME_WINDOW - Duration::SECOND)).await,
+        restart
+    );
+    assert_eq!(items(Some(at(45))).await, restart);
+    assert_eq!(items(None).await, restart);
+}
@@ -0,0 +1,186 @@
+//! An opt-in stream format that sends [RFC 6902](https://www.rfc-editor.org/rfc/rfc6902) JSON
+//! Patch deltas instead of whole stream components.
+//!
+//! Clients opt in with an `X-Before-Stream-Format: json-patch` header or a
+//! `_before_stream_format=json-patch` query parameter. Each message is then either a keyframe,
+//! `{"value": {...}}`, shaped like a regular stream event but always containing every component;
+//! or a patch, `{"patch": {"games": [...], ...}}`, containing a list of operations for each
+//! component that changed, to be applied to that component's previous value.
+//!
+//! Every stream starts with a keyframe, and a keyframe is sent in place of every
+//! [`KEYFRAME_INTERVAL`]th update so that a client which dropped a message catches up. Since a
+//! reconnecting client needs a keyframe anyway, JSON Patch streams ignore `Last-Event-ID` and start
+//! over rather than resuming.
+
+use crate::stream::Item;
+use rocket::async_trait;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde_json::{json, Map, Value};
+use std::convert::Infallible;
+
+pub(crate) const KEYFRAME_INTERVAL: usize = 10;
+
+const FORMAT_NAME: &str = "json-patch";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StreamFormat {
+    Full,
+    Patch,
+}
+
+impl StreamFormat {
+    pub(crate) fn from_name(name: &str) -> StreamFormat {
+        if name == FORMAT_NAME {
+            StreamFormat::Patch
+        } else {
+            StreamFormat::Full
+        }
+    }
+
+    pub(crate) fn patcher(self) -> Option<Patcher> {
+        match self {
+            StreamFormat::Full => None,
+            StreamFormat::Patch => Some(Patcher::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for StreamFormat {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<StreamFormat, Infallible> {
+        let name = req.headers().get_one("X-Before-Stream-Format").or_else(|| {
+            req.uri().query().and_then(|q| {
+                q.segments().find_map(|(k, v)| {
+                    if k == "_before_stream_format" {
+                        Some(v)
+                    } else {
+                        None
+                    }
+                })
+            })
+        });
+        Outcome::Success(name.map_or(StreamFormat::Full, StreamFormat::from_name))
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// Turns stream items into keyframes and patches for one client.
+#[derive(Debug, Default)]
+pub(crate) struct Patcher {
+    components: Map<String, Value>,
+    since_keyframe: usize,
+}
+
+impl Patcher {
+    pub(crate) fn next(&mut self, item: &Item) -> serde_json::Result<Value> {
+        let (value, keyframe) = match item {
+            Item::Start(_, first) => (serde_json::to_value(first)?, true),
+            Item::Update(version) => (serde_json::to_value(&version.data.value)?, false),
+        };
+        let components = match value {
+            Value::Object(components) => components,
+            _ => Map::new(),
+        };
+
+        if keyframe || self.since_keyframe + 1 >= KEYFRAME_INTERVAL {
+            self.components.extend(components);
+            self.since_keyframe = 0;
+            return Ok(json!({ "value": self.components }));
+        }
+
+        self.since_keyframe += 1;
+        let mut patch = Map::new();
+        for (name, value) in components {
+            let ops = match self.components.get(&name) {
+                Some(previous) => diff(previous, &value),
+                None => vec![json!({ "op": "add", "path": "", "value": value })],
+            };
+            if !ops.is_empty() {
+                patch.insert(name.clone(), Value::Array(ops));
+            }
+            self.components.insert(name, value);
+        }
+        Ok(json!({ "patch": patch }))
+    }
+}
+
+/// Returns JSON Patch operations that turn `from` into `to`.
+///
+/// Objects are diffed key by key and arrays of the same length element by element; anything else
+/// that differs is replaced outright.
+pub(crate) fn diff(from: &Value, to: &Value) -> Vec<Value> {
+    let mut ops = Vec::new();
+    diff_inner(&mut ops, &mut String::new(), from, to);
+    ops
+}
+
+fn diff_inner(ops: &mut Vec<Value>, path: &mut String, from: &Value, to: &Value) {
+    match (from, to) {
+        (Value::Object(from), Value::Object(to)) => {
+            for (key, old) in from {
+                with_segment(path, key, |path| match to.get(key) {
+                    Some(new) => diff_inner(ops, path, old, new),
+                    None => ops.push(json!({ "op": "remove", "path": path })),
+                });
+            }
+            for (key, new) in to {
+                if !from.contains_key(key) {
+                    with_segment(path, key, |path| {
+                        ops.push(json!({ "op": "add", "path": path, "value": new }));
+                    });
+                }
+            }
+        }
+        (Value::Array(from), Value::Array(to)) if from.len() == to.len() => {
+            for (i, (old, new)) in from.iter().zip(to).enumerate() {
+                with_segment(path, &i.to_string(), |path| diff_inner(ops, path, old, new));
+            }
+        }
+        _ => {
+            if from != to {
+                ops.push(json!({ "op": "replace", "path": path, "value": to }));
+            }
+        }
+    }
+}
+
+/// Appends an escaped JSON Pointer segment to `path` while running `f`.
+fn with_segment(path: &mut String, segment: &str, f: impl FnOnce(&mut String)) {
+    let len = path.len();
+    path.push('/');
+    path.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    f(path);
+    path.truncate(len);
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[test]
+fn diff_components() {
+    let from = json!({
+        "games": [{ "id": "a", "inning": 1 }, { "id": "b", "inning": 2 }],
+        "a/b": 1,
+        "gone": true,
+        "list": [1, 2],
+    });
+    let to = json!({
+        "games": [{ "id": "a", "inning": 2 }, { "id": "b", "inning": 2 }],
+        "a/b": 2,
+        "new": null,
+        "list": [1, 2, 3],
+    });
+    assert_eq!(
+        diff(&from, &to),
+        [
+            json!({ "op": "replace", "path": "/a~1b", "value": 2 }),
+            json!({ "op": "replace", "path": "/games/0/inning", "value": 2 }),
+            json!({ "op": "remove", "path": "/gone" }),
+            json!({ "op": "replace", "path": "/list", "value": [1, 2, 3] }),
+            json!({ "op": "add", "path": "/new", "value": null }),
+        ]
+    );
+}
@@ -0,0 +1,495 @@
+//! HTTP requests to upstream services (Chronicler and Upnuts).
+//!
+//! Every request has a timeout, and failed requests are retried with jittered exponential backoff
+//! (all of our upstream requests are idempotent GETs). Failures are reported as an
+//! [`UpstreamError`], which our routes turn into a 502 or 504 instead of a generic 500.
+//!
+//! Each upstream service can have several [`Mirrors`]. A mirror that fails with a transient error
+//! is put in a cooldown (which grows the more it fails in a row) and tried only after every healthy
+//! mirror, so a slow or broken mirror doesn't stall every request.
+//!
+//! The number of requests in flight at once can be bounded by a [`Limiter`], which hands out
+//! permits to [`Priority::Interactive`] requests (someone is waiting on them) before
+//! [`Priority::Background`] ones.
+
+use rand::{thread_rng, Rng};
+use reqwest::{Response, StatusCode};
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration as StdDuration, Instant};
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+
+const BACKOFF_BASE: StdDuration = StdDuration::from_millis(250);
+const COOLDOWN_BASE: StdDuration = StdDuration::from_secs(5);
+const COOLDOWN_MAX: StdDuration = StdDuration::from_secs(5 * 60);
+
+#[derive(Debug)]
+pub(crate) enum UpstreamError {
+    /// The upstream service responded with an error status.
+    Status(StatusCode),
+    /// The upstream service didn't respond before the timeout.
+    Timeout,
+    /// The request failed for some other reason, such as a connection error.
+    Request(reqwest::Error),
+    /// There are no mirrors to send the request to.
+    NoMirrors,
+}
+
+impl UpstreamError {
+    fn is_transient(&self) -> bool {
+        match self {
+            UpstreamError::Status(status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            UpstreamError::Timeout => true,
+            UpstreamError::Request(err) => err.is_connect() || err.is_body(),
+            UpstreamError::NoMirrors => false,
+        }
+    }
+
+    /// The status code we should respond to our own client with.
+    pub(crate) fn status(&self) -> rocket::http::Status {
+        match self {
+            UpstreamError::Timeout => rocket::http::Status::GatewayTimeout,
+            _ => rocket::http::Status::BadGateway,
+        }
+    }
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Status(status) => write!(f, "upstream responded with {status}"),
+            UpstreamError::Timeout => write!(f, "upstream timed out"),
+            UpstreamError::Request(err) => write!(f, "upstream request failed: {err}"),
+            UpstreamError::NoMirrors => write!(f, "no upstream mirrors configured"),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UpstreamError::Request(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for UpstreamError {
+    fn from(err: reqwest::Error) -> UpstreamError {
+        if err.is_timeout() {
+            UpstreamError::Timeout
+        } else if let Some(status) = err.status() {
+            UpstreamError::Status(status)
+        } else {
+            UpstreamError::Request(err)
+        }
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Client {
+    pub(crate) inner: reqwest::Client,
+    pub(crate) timeout: Option<StdDuration>,
+    pub(crate) retries: u32,
+    pub(crate) limiter: Option<Arc<Limiter>>,
+}
+
+impl Client {
+    /// Sends a GET request for `path` (appended to a mirror's base URL), returning the response
+    /// once headers are received.
+    ///
+    /// The timeout only applies to receiving the headers, so that large bodies can be streamed.
+    pub(crate) async fn get(
+        &self,
+        mirrors: &Mirrors,
+        path: &str,
+        priority: Priority,
+    ) -> Result<Response, UpstreamError> {
+        self.failover(mirrors, path, priority, |url| async move {
+            let send = self.inner.get(url).send();
+            let response = match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, send)
+                    .await
+                    .map_err(|_| UpstreamError::Timeout)??,
+                None => send.await?,
+            };
+            Ok(response.error_for_status()?)
+        })
+        .await
+    }
+
+    /// Sends a GET request for `path` (appended to a mirror's base URL) and reads the entire
+    /// response body.
+    pub(crate) async fn get_text(
+        &self,
+        mirrors: &Mirrors,
+        path: &str,
+        priority: Priority,
+    ) -> Result<String, UpstreamError> {
+        self.failover(mirrors, path, priority, |url| async move {
+            let mut request = self.inner.get(url);
+            if let Some(timeout) = self.timeout {
+                request = request.timeout(timeout);
+            }
+            Ok(request.send().await?.error_for_status()?.text().await?)
+        })
+        .await
+    }
+
+    async fn failover<F, Fut, T>(
+        &self,
+        mirrors: &Mirrors,
+        path: &str,
+        priority: Priority,
+        f: F,
+    ) -> Result<T, UpstreamError>
+    where
+        F: Fn(String) -> Fut,
+        Fut: std::future::Future<Output = Result<T, UpstreamError>>,
+    {
+        self.retry(|| async {
+            let mut last = UpstreamError::NoMirrors;
+            for mirror in mirrors.order() {
+                let _permit = match &self.limiter {
+                    Some(limiter) => Some(limiter.acquire(priority).await),
+                    None => None,
+                };
+                match f(format!("{}{}", mirror.base_url, path)).await {
+                    Ok(t) => {
+                        mirror.succeeded();
+                        return Ok(t);
+                    }
+                    Err(err) if err.is_transient() => {
+                        log::warn!("{}: {}", mirror.base_url, err);
+                        mirror.failed();
+                        last = err;
+                    }
+                    // any other mirror would give us the same answer
+                    Err(err) => return Err(err),
+                }
+            }
+            Err(last)
+        })
+        .await
+    }
+
+    async fn retry<F, Fut, T>(&self, f: F) -> Result<T, UpstreamError>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<T, UpstreamError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Err(err) if attempt < self.retries && err.is_transient() => {
+                    let max = BACKOFF_BASE * 2_u32.saturating_pow(attempt);
+                    let delay = thread_rng().gen_range(StdDuration::ZERO..=max);
+                    log::warn!("{err}, retrying in {delay:?}");
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[derive(Debug, Default)]
+pub(crate) struct Mirrors {
+    list: Vec<Mirror>,
+    round_robin: bool,
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Mirror {
+    base_url: String,
+    failures: AtomicU32,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Mirrors {
+    /// If `round_robin` is set, requests start at the next healthy mirror in turn; otherwise they
+    /// start at the first healthy mirror in `base_urls`.
+    pub(crate) fn new(base_urls: &[String], round_robin: bool) -> Mirrors {
+        Mirrors {
+            list: base_urls
+                .iter()
+                .map(|base_url| Mirror {
+                    base_url: base_url.clone(),
+                    failures: AtomicU32::new(0),
+                    down_until: Mutex::new(None),
+                })
+                .collect(),
+            round_robin,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the order to try mirrors in for a request: healthy mirrors first, then mirrors in
+    /// cooldown, soonest to recover first.
+    fn order(&self) -> Vec<&Mirror> {
+        let start = if self.round_robin && !self.list.is_empty() {
+            self.next.fetch_add(1, Ordering::Relaxed) % self.list.len()
+        } else {
+            0
+        };
+        let now = Instant::now();
+        let mut order = self.list[start..]
+            .iter()
+            .chain(&self.list[..start])
+            .collect::<Vec<_>>();
+        // `None` sorts first, and the sort is stable
+        order.sort_by_key(|mirror| mirror.down_until().filter(|t| *t > now));
+        order
+    }
+}
+
+impl Mirror {
+    fn down_until(&self) -> Option<Instant> {
+        *self.down_until.lock().unwrap()
+    }
+
+    fn failed(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed);
+        let cooldown = COOLDOWN_BASE
+            .checked_mul(2_u32.saturating_pow(failures))
+            .map_or(COOLDOWN_MAX, |d| d.min(COOLDOWN_MAX));
+        *self.down_until.lock().unwrap() = Some(Instant::now() + cooldown);
+    }
+
+    fn succeeded(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        *self.down_until.lock().unwrap() = None;
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Priority {
+    /// Someone is waiting on this request.
+    Interactive,
+    /// Nobody is waiting on this request in particular, such as paging through every player.
+    Background,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Interactive
+    }
+}
+
+/// A semaphore that wakes interactive waiters before background waiters.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    available: usize,
+    interactive: VecDeque<oneshot::Sender<()>>,
+    background: VecDeque<oneshot::Sender<()>>,
+}
+
+pub(crate) struct Permit<'a>(&'a Limiter);
+
+/// Gives back a permit that was handed to a waiter that stopped waiting.
+struct Waiter<'a> {
+    limiter: &'a Limiter,
+    rx: oneshot::Receiver<()>,
+    acquired: bool,
+}
+
+impl Limiter {
+    pub(crate) fn new(permits: usize) -> Limiter {
+        Limiter {
+            state: Mutex::new(LimiterState {
+                available: permits,
+                interactive: VecDeque::new(),
+                background: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub(crate) async fn acquire(&self, priority: Priority) -> Permit<'_> {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            let queued = match priority {
+                Priority::Interactive => state.interactive.is_empty(),
+                Priority::Background => state.interactive.is_empty() && state.background.is_empty(),
+            };
+            if state.available > 0 && queued {
+                state.available -= 1;
+                return Permit(self);
+            }
+            let (tx, rx) = oneshot::channel();
+            match priority {
+                Priority::Interactive => state.interactive.push_back(tx),
+                Priority::Background => state.background.push_back(tx),
+            }
+            rx
+        };
+        let mut waiter = Waiter {
+            limiter: self,
+            rx,
+            acquired: false,
+        };
+        // the sender is only dropped after sending
+        let _ = (&mut waiter.rx).await;
+        waiter.acquired = true;
+        Permit(self)
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(tx) = state
+            .interactive
+            .pop_front()
+            .or_else(|| state.background.pop_front())
+        {
+            if tx.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if !self.acquired {
+            self.rx.close();
+            if self.rx.try_recv().is_ok() {
+                self.limiter.release();
+            }
+        }
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// Serves each of `statuses` in turn to successive connections.
+#[cfg(test)]
+async fn serve(statuses: &'static [u16]) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        for status in statuses {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = socket.read(&mut buf).await.unwrap();
+            let response =
+                format!("HTTP/1.1 {status} X\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+            socket.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    format!("http://{addr}/")
+}
+
+#[cfg(test)]
+fn mirrors(base_urls: &[String]) -> Mirrors {
+    Mirrors::new(base_urls, false)
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn retry_transient_errors() {
+    use rocket::http::Status;
+
+    let client = Client {
+        retries: 2,
+        ..Client::default()
+    };
+
+    let url = serve(&[503, 502, 200]).await;
+    let result = client
+        .get_text(&mirrors(&[url]), "", Priority::Interactive)
+        .await;
+    assert_eq!(result.unwrap(), "ok");
+
+    let url = serve(&[404, 200]).await;
+    let err = client
+        .get_text(&mirrors(&[url]), "", Priority::Interactive)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, UpstreamError::Status(s) if s.as_u16() == 404));
+    assert_eq!(err.status(), Status::BadGateway);
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn fail_over_to_healthy_mirror() {
+    let client = Client::default();
+    let mirrors = mirrors(&[serve(&[503]).await, serve(&[200, 200]).await]);
+
+    assert_eq!(
+        client
+            .get_text(&mirrors, "", Priority::Interactive)
+            .await
+            .unwrap(),
+        "ok"
+    );
+    // the first mirror is in cooldown, so this goes straight to the second mirror
+    assert_eq!(
+        client
+            .get_text(&mirrors, "", Priority::Interactive)
+            .await
+            .unwrap(),
+        "ok"
+    );
+
+    let err = client
+        .get_text(&Mirrors::default(), "", Priority::Interactive)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, UpstreamError::NoMirrors));
+}
+
+#[cfg(test)]
+#[rocket::async_test]
+async fn interactive_before_background() {
+    let limiter = Limiter::new(1);
+    let permit = limiter.acquire(Priority::Background).await;
+
+    let order = std::sync::Mutex::new(Vec::new());
+    let acquire = |priority| {
+        let (limiter, order) = (&limiter, &order);
+        async move {
+            let _permit = limiter.acquire(priority).await;
+            order.lock().unwrap().push(priority);
+        }
+    };
+    let release = async move {
+        tokio::task::yield_now().await;
+        drop(permit);
+    };
+    tokio::join!(
+        acquire(Priority::Background),
+        acquire(Priority::Interactive),
+        release
+    );
+    assert_eq!(
+        *order.lock().unwrap(),
+        [Priority::Interactive, Priority::Background]
+    );
+}
@@ -14,6 +14,7 @@ use rocket::response::status::NoContent;
 use rocket::{get, post, State};
 use serde::Serialize;
 use serde_json::{json, Value};
+use std::sync::Arc;
 
 const SIM_NO_COIN: DateTime = datetime!(2021-07-30 03:00:15.845649 UTC);
 
@@ -117,7 +118,7 @@ pub(crate) fn get_user(cookies: &CookieJar<'_>, time: OffsetTime) -> Value {
 
 #[get("/api/getUserNotifications")]
 pub(crate) async fn get_user_notifications(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     cookies: &CookieJar<'_>,
     time: OffsetTime,
 ) -> Result<Value> {
@@ -137,7 +138,7 @@ pub(crate) fn clear_user_notifications() -> NoContent {
 
 #[get("/api/getUserRewards")]
 pub(crate) async fn get_user_rewards(
-    config: &State<Config>,
+    config: &State<Arc<Config>>,
     cookies: &CookieJar<'_>,
     time: OffsetTime,
 ) -> Result<Value> {
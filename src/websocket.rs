@@ -0,0 +1,358 @@
+//! A WebSocket transport for the replay stream, for dashboards and bots that would rather hold one
+//! connection open than reopen an `EventSource` every 40 seconds.
+//!
+//! Rocket can't upgrade connections (yet), so this listens on its own port,
+//! `websocket_port`, and serves `/events/streamData`. The perceived time comes from the same
+//! `offset_sec` and `playback_rate` cookies or `_before_offset_time` and `_before_rate` query
+//! parameters the other transports use.
+//!
+//! Each text message sent to the client is the same JSON payload as an `/events/streamData` event,
+//! or a keyframe or patch if the client opted into the JSON Patch format (see
+//! [`stream::patch`](crate::stream::patch)).
+//! The stream restarts every 40 seconds, starting with a fresh `Item::Start`, just like the
+//! frontend's `EventSource`. Clients can change the perceived time without reconnecting by sending
+//! a text message:
+//!
+//! - `{"offset": 3600}` views the stream one hour behind current time
+//! - `{"time": "2020-10-18T00:00:00Z"}` jumps to a point in time
+//! - `{"rate": 2.0}` plays back at twice the speed, continuing from the current perceived time
+//! - `{"paused": true}` pauses perceived time, and `{"paused": false}` resumes it
+//!
+//! Control messages are answered with the stream restarting from the new time, or with an
+//! `{"error": "..."}` message if they couldn't be understood.
+
+use crate::cookies::AsCookie;
+use crate::offset::{Clock, Offset, Playback, MAX_RATE};
+use crate::stream::patch::StreamFormat;
+use crate::stream::{self, Item};
+use crate::time::DateTime;
+use crate::Config;
+use anyhow::{anyhow, Result};
+use rocket::futures::{SinkExt, Stream, StreamExt};
+use rocket::http::uri::Origin;
+use rocket::http::Cookie;
+use rocket::Shutdown;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+const RESTART_INTERVAL: StdDuration = StdDuration::from_secs(40);
+
+pub(crate) async fn serve(config: Arc<Config>, addr: SocketAddr, shutdown: Shutdown) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("failed to bind WebSocket listener to {addr}: {err}");
+            return;
+        }
+    };
+    log::info!("WebSocket stream listening on ws://{addr}/events/streamData");
+
+    let mut stop = shutdown.clone();
+    loop {
+        let socket = select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                Err(err) => {
+                    log::warn!("failed to accept WebSocket connection: {err}");
+                    continue;
+                }
+            },
+            () = &mut stop => break,
+        };
+        let (config, shutdown) = (config.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            if let Err(err) = connection(&config, socket, shutdown).await {
+                log::debug!("WebSocket connection closed: {err:#}");
+            }
+        });
+    }
+}
+
+/// A control message. Exactly one field is set. (This isn't an untagged enum because those can't
+/// hold floats with `serde_json`'s `arbitrary_precision` feature.)
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Control {
+    offset: Option<i64>,
+    time: Option<DateTime>,
+    rate: Option<f64>,
+    paused: Option<bool>,
+}
+
+/// The handshake callback, which reads the perceived time and stream format from the upgrade
+/// request.
+struct Handshake {
+    clock: Option<Clock>,
+    format: StreamFormat,
+}
+
+impl Callback for &mut Handshake {
+    fn on_request(self, req: &Request, resp: Response) -> Result<Response, ErrorResponse> {
+        if req.uri().path() != "/events/streamData" {
+            return Err(error_response(StatusCode::NOT_FOUND, "not found"));
+        }
+        self.clock = request_clock(req);
+        self.format = request_format(req);
+        if self.clock.is_none() {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                "offset not present",
+            ));
+        }
+        Ok(resp)
+    }
+}
+
+async fn connection(config: &Arc<Config>, socket: TcpStream, mut shutdown: Shutdown) -> Result<()> {
+    let mut handshake = Handshake {
+        clock: None,
+        format: StreamFormat::Full,
+    };
+    let mut ws = tokio_tungstenite::accept_hdr_async(socket, &mut handshake).await?;
+    let format = handshake.format;
+    let mut clock = handshake.clock.unwrap_or(Clock {
+        offset: Offset(Duration::ZERO),
+        playback: Playback::default(),
+    });
+
+    let mut restart = interval_at(Instant::now(), RESTART_INTERVAL);
+    restart.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut items: Option<Items> = None;
+    let mut patcher = format.patcher();
+    loop {
+        select! {
+            _ = restart.tick() => {
+                items = start(config, &mut ws, clock, shutdown.clone()).await?;
+            }
+            item = async { items.as_mut()?.next().await }, if items.is_some() => match item {
+                Some(item) => {
+                    let message = match &mut patcher {
+                        Some(patcher) => patcher.next(&item)?.to_string(),
+                        None => item.to_json()?.to_string(),
+                    };
+                    ws.send(Message::Text(message)).await?;
+                }
+                // wait for the next restart
+                None => items = None,
+            },
+            message = ws.next() => match message.transpose()? {
+                Some(Message::Text(text)) => match control(clock, &text) {
+                    Ok(new) => {
+                        clock = new;
+                        restart.reset();
+                        items = start(config, &mut ws, clock, shutdown.clone()).await?;
+                    }
+                    Err(err) => {
+                        send_error(&mut ws, format!("invalid control message: {err}")).await?;
+                    }
+                },
+                Some(Message::Close(_)) | None => break,
+                // pings are answered automatically
+                Some(_) => {}
+            },
+            () = &mut shutdown => break,
+        }
+    }
+    let _ = ws.close(None).await;
+    Ok(())
+}
+
+fn control(clock: Clock, text: &str) -> Result<Clock> {
+    match serde_json::from_str(text)? {
+        Control {
+            offset: Some(offset),
+            time: None,
+            rate: None,
+            paused: None,
+        } => {
+            let now = DateTime::now();
+            clock.jump(now - Duration::seconds(offset), now)
+        }
+        Control {
+            offset: None,
+            time: Some(time),
+            rate: None,
+            paused: None,
+        } => clock.jump(time, DateTime::now()),
+        Control {
+            offset: None,
+            time: None,
+            rate: Some(rate),
+            paused: None,
+        } => {
+            if rate > 0.0 && rate <= MAX_RATE {
+                clock.with_rate(rate)
+            } else {
+                Err(anyhow!(
+                    "rate must be greater than 0 and at most {MAX_RATE}"
+                ))
+            }
+        }
+        Control {
+            offset: None,
+            time: None,
+            rate: None,
+            paused: Some(paused),
+        } => clock.with_paused(paused),
+        _ => Err(anyhow!(
+            "expected exactly one of `offset`, `time`, `rate` or `paused`"
+        )),
+    }
+}
+
+type Items = Pin<Box<dyn Stream<Item = Item> + Send>>;
+
+/// Starts the stream at the current perceived time. If that fails, the error is sent to the client
+/// and the connection stays open, so that the client can try another time.
+async fn start(
+    config: &Arc<Config>,
+    ws: &mut WebSocketStream<TcpStream>,
+    clock: Clock,
+    shutdown: Shutdown,
+) -> Result<Option<Items>> {
+    match stream::start(config, clock, None, shutdown).await {
+        Ok(items) => Ok(Some(Box::pin(items))),
+        Err(err) => {
+            send_error(ws, format!("{err:#}")).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn send_error(ws: &mut WebSocketStream<TcpStream>, error: String) -> Result<()> {
+    let message = json!({ "error": error }).to_string();
+    Ok(ws.send(Message::Text(message)).await?)
+}
+
+/// Reads the perceived time offset and playback rate from the handshake request, as [`Clock`]'s
+/// request guard does.
+fn request_clock(req: &Request) -> Option<Clock> {
+    let query = |name: &str| query_param(req, name);
+    let cookie = |name: &str| {
+        req.headers()
+            .get_all("cookie")
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|cookie| Cookie::parse(cookie.trim()).ok())
+            .find(|cookie| cookie.name() == name)
+            .map(|cookie| cookie.value().to_owned())
+    };
+
+    let offset = query("_before_offset_time")
+        .and_then(|v| v.parse().ok())
+        .or_else(|| cookie(Offset::NAME).and_then(|v| v.parse().ok()))?;
+    let playback = query("_before_rate")
+        .and_then(|v| v.parse().ok())
+        .or_else(|| cookie(Playback::NAME).and_then(|v| v.parse().ok()))
+        .unwrap_or_default();
+    Some(Clock { offset, playback })
+}
+
+fn request_format(req: &Request) -> StreamFormat {
+    req.headers()
+        .get("X-Before-Stream-Format")
+        .and_then(|header| header.to_str().ok())
+        .map(str::to_owned)
+        .or_else(|| query_param(req, "_before_stream_format"))
+        .map_or(StreamFormat::Full, |name| StreamFormat::from_name(&name))
+}
+
+/// Returns a percent-decoded query parameter from the handshake request, decoded the same way
+/// Rocket decodes them for the other transports.
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    let uri = Origin::parse(req.uri().path_and_query()?.as_str()).ok()?;
+    let value = uri
+        .query()?
+        .segments()
+        .find_map(|(k, v)| (k == name).then(|| v.to_owned()));
+    value
+}
+
+fn error_response(status: StatusCode, message: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(message.to_owned()));
+    *response.status_mut() = status;
+    response
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+#[test]
+fn handshake_clock() {
+    let request = |uri: &str| Request::builder().uri(uri).body(()).unwrap();
+
+    let clock = request_clock(&request(
+        "/events/streamData?_before_offset_time=3600&_before_rate=2%401614556800%40paused",
+    ))
+    .unwrap();
+    assert_eq!(clock.offset.0, Duration::hours(1));
+    assert!((clock.playback.rate - 2.0).abs() < f64::EPSILON);
+    assert_eq!(clock.playback.anchor.0.unix_timestamp(), 1_614_556_800);
+    assert!(clock.playback.paused);
+
+    let clock = request_clock(&request("/events/streamData?_before_offset_time=60")).unwrap();
+    assert!(clock.playback.is_realtime());
+    assert!(request_clock(&request("/events/streamData")).is_none());
+    assert_eq!(
+        query_param(
+            &request("/events/streamData?a=1&_before_stream_format=json%2Bpatch"),
+            "_before_stream_format"
+        )
+        .as_deref(),
+        Some("json+patch")
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn control_messages() {
+    use crate::time::datetime;
+
+    let near = |a: DateTime, b: DateTime| (a - b).abs() <= Duration::seconds(2);
+    let clock = Clock {
+        offset: Offset(Duration::ZERO),
+        playback: Playback::default(),
+    };
+
+    let behind = control(clock, r#"{"offset": 3600}"#).unwrap();
+    assert!(near(behind.now(), DateTime::now() - Duration::hours(1)));
+
+    let time = datetime!(2020-10-18 00:00:00 UTC);
+    let jumped = control(clock, r#"{"time": "2020-10-18T00:00:00Z"}"#).unwrap();
+    assert!(near(jumped.now(), time));
+
+    let fast = control(jumped, r#"{"rate": 2.0}"#).unwrap();
+    assert!((fast.playback.rate - 2.0).abs() < f64::EPSILON);
+    assert!(near(fast.now(), time));
+    assert!(control(jumped, r#"{"rate": 0}"#).is_err());
+    assert!(control(jumped, &format!(r#"{{"rate": {}}}"#, MAX_RATE * 2.0)).is_err());
+
+    let paused = control(fast, r#"{"paused": true}"#).unwrap();
+    assert!(paused.playback.paused);
+    assert!((paused.playback.rate - 2.0).abs() < f64::EPSILON);
+    assert_eq!(
+        paused.time_at(DateTime::now() + Duration::hours(1)),
+        paused.now()
+    );
+    let resumed = control(paused, r#"{"paused": false}"#).unwrap();
+    assert!(!resumed.playback.paused);
+    assert!(near(resumed.now(), paused.now()));
+
+    assert!(control(clock, r#"{"speed": 2}"#).is_err());
+    assert!(control(clock, r#"{"offset": 60, "paused": true}"#).is_err());
+}